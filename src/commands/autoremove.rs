@@ -0,0 +1,149 @@
+use crate::db::Db;
+use crate::error::LuluError;
+use crate::model::BuildDependency;
+use crate::utils::db::{open_and_lock_db, unlock_db};
+use crate::utils::privileges::run_as_root;
+use crate::utils::process::start_id;
+use crate::{success, title, warning};
+use libc::{getpid, kill};
+use log::trace;
+use rust_apt::cache::{Cache, PackageSort};
+use rust_apt::raw::progress::{AptAcquireProgress, AptInstallProgress};
+use std::collections::BTreeSet;
+use yansi::{Color, Paint};
+
+pub fn autoremove() -> Result<(), LuluError> {
+    run_as_root("autoremove")?;
+    let mut db = open_and_lock_db()?;
+
+    let result = autoremove_locked(&db);
+
+    unlock_db(&mut db)?;
+    result
+}
+
+fn autoremove_locked(db: &Db) -> Result<(), LuluError> {
+    title!("🧹", "Looking for leftover build dependencies");
+    let cache = Cache::new::<bool>(&[])
+        .map_err(|e| LuluError::Apt(format!("Failed to open apt cache: {}", e)))?;
+
+    let unneeded = unneeded_packages(&cache);
+    let mut to_forget = Vec::<String>::new();
+    let mut to_uninstall = Vec::<String>::new();
+    for d in db.clone().collection("build_dependencies").get() {
+        let dependency = match d.doc.clone().get::<BuildDependency>() {
+            Ok(Some(dependency)) => dependency,
+            _ => {
+                warning!("Failed to read build dependency {}", d.id);
+                continue;
+            }
+        };
+        if is_running(&dependency) {
+            trace!(
+                "Keeping {} as the build of {} is still running",
+                d.id,
+                dependency.package
+            );
+            continue;
+        }
+
+        match cache.get(&d.id) {
+            // Packages installed manually since are no longer auto installed
+            Some(pkg) if pkg.is_installed() && pkg.is_auto_installed() => {
+                success!(
+                    "Found package: {}  \tLeft by {}",
+                    Paint::cyan(pkg.name()).italic(),
+                    dependency.package
+                );
+                pkg.mark_delete(false);
+                pkg.protect();
+                to_uninstall.push(d.id.clone());
+            }
+            _ => trace!("Forgetting {} as it is no longer a build dependency", d.id),
+        }
+        to_forget.push(d.id);
+    }
+
+    // Dependencies apt pulled in with them
+    if !to_uninstall.is_empty() {
+        for name in mark_unneeded(&cache, &unneeded) {
+            success!(
+                "Found package: {}  \tOnly needed by leftovers",
+                Paint::cyan(&name).italic()
+            );
+            to_uninstall.push(name);
+        }
+    }
+
+    if !to_uninstall.is_empty() {
+        title!("📦", "Uninstalling leftover build dependencies");
+        let mut acquire_progress = AptAcquireProgress::new_box();
+        let mut install_progress = AptInstallProgress::new_box();
+        cache
+            .commit(&mut acquire_progress, &mut install_progress)
+            .map_err(|e| {
+                LuluError::Apt(format!(
+                    "Failed to uninstall {}: {}",
+                    to_uninstall.join(", "),
+                    e
+                ))
+            })?;
+    }
+
+    for name in &to_forget {
+        db.clone()
+            .collection("build_dependencies")
+            .doc(name)
+            .delete()
+            .map_err(|e| {
+                LuluError::Db(format!("Failed to forget build dependency {}: {}", name, e))
+            })?;
+    }
+
+    if to_uninstall.is_empty() {
+        success!("No leftover build dependencies");
+    } else {
+        success!("Removed {}", to_uninstall.join(", "));
+    }
+    Ok(())
+}
+
+/// Installed packages which nothing needs anymore
+pub fn unneeded_packages(cache: &Cache) -> BTreeSet<String> {
+    let sort = PackageSort::default().installed().auto_removable();
+    cache
+        .packages(&sort)
+        .map(|pkg| pkg.name().to_string())
+        .collect()
+}
+
+/// Mark for removal the packages which became unneeded since `before`, like `apt autoremove`
+///
+/// Those are the automatically installed dependencies of the packages marked for removal, packages
+/// which were already unneeded are left to the user. Return their names.
+pub fn mark_unneeded(cache: &Cache, before: &BTreeSet<String>) -> Vec<String> {
+    let sort = PackageSort::default().installed().auto_removable();
+    let mut marked = vec![];
+    for pkg in cache.packages(&sort) {
+        if before.contains(pkg.name()) {
+            continue;
+        }
+        pkg.mark_delete(false);
+        pkg.protect();
+        marked.push(pkg.name().to_string());
+    }
+    marked
+}
+
+/// Whether the build which recorded this dependency is still running
+fn is_running(dependency: &BuildDependency) -> bool {
+    let pid = dependency.pid;
+    if pid == unsafe { getpid() } || unsafe { kill(pid, 0) } != 0 {
+        return false;
+    }
+    // Records without a start time can only be checked by pid
+    match &dependency.started {
+        Some(started) => start_id(pid).as_ref() == Some(started),
+        None => true,
+    }
+}
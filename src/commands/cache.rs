@@ -0,0 +1,41 @@
+use crate::error::LuluError;
+use crate::utils::source_cache::{cache_dir, format_size, mirrors, size};
+use crate::{error, success, title};
+use yansi::{Color, Paint};
+
+pub fn cache(clean: bool) -> Result<(), LuluError> {
+    title!(
+        "🗃",
+        "Source cache in {}",
+        Paint::cyan(cache_dir().display()).underline()
+    );
+
+    let mut total = 0;
+    for mirror in mirrors() {
+        let mirror_size = size(&mirror.path);
+        total += mirror_size;
+        println!(
+            "{}\t{}",
+            Paint::cyan(mirror.url.clone()).bold(),
+            format_size(mirror_size)
+        );
+
+        if clean {
+            match std::fs::remove_dir_all(&mirror.path) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to remove mirror of {}", mirror.url);
+                    eprintln!("{:?}", e);
+                }
+            }
+        }
+    }
+    println!();
+
+    if clean {
+        success!("Freed {}", format_size(total));
+    } else {
+        success!("Total {}", format_size(total));
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs::read_to_string;
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::process::Stdio;
 use std::{
     env,
@@ -8,8 +10,9 @@ use std::{
     process::Command,
 };
 
-use deb_rust::{binary::DebPackage, DebArchitecture};
-use git2::{DescribeOptions, Repository};
+use deb_rust::binary::{DebFile, DebPackage};
+use deb_rust::DebPriority;
+use git2::{DescribeFormatOptions, DescribeOptions, Repository};
 use log::trace;
 use rust_apt::{
     cache::Cache,
@@ -18,33 +21,112 @@ use rust_apt::{
 };
 use yansi::{Color, Paint};
 
+use crate::commands::autoremove::{mark_unneeded, unneeded_packages};
 use crate::db::Db;
-use crate::model::{DbPackage, Installed};
-use crate::utils::db::open_db;
+use crate::error::LuluError;
+use crate::model::{BuildConfig, BuildDependency, DbPackage, Installed, VerifyConfig};
+use crate::utils::arch::{deb_architecture, host_architecture, package_architecture};
+use crate::utils::build_log::BuildLog;
+use crate::utils::config::{read_config, CONFIG_PATH};
+use crate::utils::db::{open_db, with_lock, DB_PATH};
+use crate::utils::env::{self as script_env, ScriptEnv};
+use crate::utils::isolation::{archive_path, check_bwrap, BuildRoot, BWRAP_MIN_VERSION};
 use crate::utils::lulu::{fork_wait, lulu_file};
+use crate::utils::privileges::run_as_root;
+use crate::utils::process::start_id;
+use crate::utils::relation::Relation;
+use crate::utils::signature::{find_signature, normalize_fingerprint, verify, KEYRING_PATH};
+use crate::utils::source_cache::{self, Mirror};
+use crate::utils::sources;
+use crate::utils::version as versions;
 use crate::{
-    error,
-    package::{DependencyType, Lulu},
+    package::{Dependency, DependencyType, Lulu, Output, Package},
     success, tip, title, warning,
 };
 
-fn install_local(ctx: &mut Context) {
-    let deserialized = match lulu_file("LULU.toml") {
-        Ok(f) => f.unwrap(),
-        Err(e) => {
-            error!("LULU.toml is not readable");
-            panic!("{:?}", e)
+/// Find the recipe of a package, the directory where it's built and the repository it comes from
+///
+/// `name` can be a directory containing a LULU.toml file, a package of a repository (`name` or
+/// `repository/name`) or a git repository, the current directory is used when there is none.
+fn fetch_recipe(
+    name: Option<String>,
+    ctx: &Context,
+) -> Result<(PathBuf, Lulu, Option<String>), LuluError> {
+    let (path, repository) = match name {
+        Some(n) if n.contains("://") || n.starts_with("git@") => {
+            (fetch_git(&n, ctx.options.keep_srcdir)?, None)
+        }
+        Some(n) if Path::new(&n).join("LULU.toml").is_file() => {
+            let path = Path::new(&n)
+                .canonicalize()
+                .map_err(|e| LuluError::Other(format!("Failed to resolve {}: {}", n, e)))?;
+            (path, None)
+        }
+        Some(n) => {
+            let (path, repository) = match n.split_once('/') {
+                Some((repository, name)) => fetch_db(name, Some(repository), ctx)?,
+                None => fetch_db(&n, None, ctx)?,
+            };
+            (path, Some(repository))
         }
+        None => (
+            env::current_dir()
+                .map_err(|e| LuluError::Other(format!("Failed to get current directory: {}", e)))?,
+            None,
+        ),
     };
+    let lulu = read_lulu(&path.join("LULU.toml"))?;
+
+    Ok((path, lulu, repository))
+}
+
+/// Read and parse a LULU.toml file
+fn read_lulu(path: &Path) -> Result<Lulu, LuluError> {
+    match lulu_file(path) {
+        Ok(f) => f.map_err(|e| {
+            LuluError::Manifest(format!("{} is not deserializable: {}", path.display(), e))
+        }),
+        Err(e) => Err(LuluError::Manifest(format!(
+            "{} is not readable: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Working directory of a source repository
+fn source_dir(repo: &Repository) -> Result<PathBuf, LuluError> {
+    repo.path()
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or(LuluError::Git(format!(
+            "{} has no working directory",
+            repo.path().display()
+        )))
+}
 
-    install_with_ctx(env::current_dir().unwrap(), deserialized, ctx);
+fn set_current_dir(path: &Path) -> Result<(), LuluError> {
+    env::set_current_dir(path).map_err(|e| {
+        LuluError::Other(format!(
+            "Failed to change directory to {}: {}",
+            path.display(),
+            e
+        ))
+    })
 }
 
-fn install_git(url: String, ctx: &mut Context) {
+/// Clone a git repository containing a LULU.toml file and return where it was cloned
+///
+/// With `keep`, a previous clone is reused so that the source directory it contains is kept.
+fn fetch_git(url: &str, keep: bool) -> Result<PathBuf, LuluError> {
     let path = env::temp_dir().join(format!("lulu_{}", url.replace(":", "_").replace("/", "_")));
-    let mut builder = DirBuilder::new();
-    builder.recursive(true);
-    builder.create(path.clone().into_os_string()).unwrap();
+
+    if keep && Repository::open(&path).is_ok() {
+        trace!("Reusing {}", path.display());
+        return Ok(path);
+    }
+
+    let mirror = fetch_mirror(url)?;
 
     title!(
         "🔎",
@@ -52,528 +134,2139 @@ fn install_git(url: String, ctx: &mut Context) {
         Paint::cyan(path.clone().display()).underline()
     );
 
-    let status = fork_wait(|| {
-        let _repo = match Repository::clone(&url, path.clone()) {
-            Ok(repo) => repo,
-            Err(e) => {
-                error!("Failed to clone repository");
-                panic!("{:?}", e)
-            }
-        };
-    });
+    fork_wait(|| {
+        source_cache::checkout(&mirror, &path)
+            .map_err(|e| LuluError::Git(format!("Failed to clone repository: {}", e)))?;
+        Ok(())
+    })?;
 
-    if status != 0 {
-        error!("Something went wrong");
-        return;
-    }
+    Ok(path)
+}
 
-    env::set_current_dir(path.display().to_string()).unwrap();
-    install_local(ctx);
+/// Create or update the mirror of a repository in the source cache
+fn fetch_mirror(url: &str) -> Result<Mirror, LuluError> {
+    title!("🔄", "Fetching {}", Paint::cyan(url).underline());
+    source_cache::mirror(url).map_err(|e| LuluError::Git(format!("Failed to fetch {}: {}", url, e)))
 }
 
-fn install_db(name: String, ctx: &mut Context) {
-    let document = ctx.clone().db.collection("packages").doc(name.as_str());
+/// Copy the recipe directory of a package known by the db and return where it was copied with
+/// the repository it comes from
+///
+/// The recipe of the repository with the highest priority is used unless `repository` is given.
+fn fetch_db(
+    name: &str,
+    repository: Option<&str>,
+    ctx: &Context,
+) -> Result<(PathBuf, String), LuluError> {
+    let db = ctx.db.clone().ok_or(LuluError::Db(
+        "Lulu is not set up, run `lulu setup` first".to_string(),
+    ))?;
+    let document = db.collection("packages").doc(name);
     if !document.exist {
-        error!("Package {} not found", name);
-        panic!("Package {} not found", name);
+        return Err(LuluError::PackageNotFound(format!(
+            "Package {} not found",
+            name
+        )));
     }
 
     let package = match document.get::<DbPackage>() {
         Ok(p) => match p {
-            None => {
-                error!("Document seems to be empty");
-                panic!("Document seems to be empty");
-            }
+            None => return Err(LuluError::Db("Document seems to be empty".to_string())),
             Some(p) => p,
         },
-        Err(e) => {
-            error!("Failed to read document");
-            panic!("{:?}", e);
-        }
+        Err(e) => return Err(LuluError::Db(format!("Failed to read document: {}", e))),
     };
+    let recipe = package
+        .recipe(repository)
+        .ok_or(LuluError::PackageNotFound(format!(
+            "Package {} not found in repository {}",
+            name,
+            repository.unwrap_or_default()
+        )))?;
+
+    // Repositories read in place can change between updates
+    if !Path::new(&recipe.path).join("LULU.toml").is_file() {
+        return Err(LuluError::PackageNotFound(format!(
+            "Recipe of {} is no longer in {}, run `lulu update`",
+            name, recipe.path
+        )));
+    }
 
     let path = env::temp_dir().join(format!("lulu_{}", name));
 
-    let status = fork_wait(|| {
+    fork_wait(|| {
         let mut builder = DirBuilder::new();
         builder.recursive(true);
-        builder.create(path.clone().into_os_string()).unwrap();
-
-        match std::fs::copy(
-            Path::new(&package.path).join("LULU.toml"),
-            path.join("LULU.toml"),
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Failed to copy LULU.toml");
-                panic!("{:?}", e);
+        builder
+            .create(path.clone().into_os_string())
+            .map_err(|e| LuluError::Other(format!("Failed to create {}: {}", path.display(), e)))?;
+
+        // Files next to LULU.toml can be referenced by `sources`
+        sources::copy_dir(Path::new(&recipe.path), &path)
+            .map_err(|e| LuluError::Manifest(format!("Failed to copy recipe: {}", e)))?;
+        Ok(())
+    })?;
+
+    Ok((path, recipe.repository))
+}
+
+/// A git dependency ready to be built
+struct GitDependency {
+    path: PathBuf,
+    lulu: Lulu,
+    repository: Option<String>,
+}
+
+/// Find git dependencies of a package and sort them so that each one comes after its own
+/// git dependencies
+///
+/// Build dependencies are only considered when `build` is true, runtime and optional ones when
+/// `runtime` is true. Dependencies of git dependencies are always considered, except their build
+/// dependencies in isolated builds since they get them in their own build root. Already
+/// installed packages are skipped.
+fn resolve_git_dependencies(
+    lulu: &Lulu,
+    build: bool,
+    runtime: bool,
+    ctx: &Context,
+) -> Result<Vec<GitDependency>, LuluError> {
+    let mut stack = vec![lulu.package.name.clone()];
+    let mut visited = HashSet::<String>::new();
+    let mut order = Vec::<GitDependency>::new();
+
+    visit_git_dependencies(
+        lulu,
+        build,
+        runtime,
+        ctx,
+        &mut stack,
+        &mut visited,
+        &mut order,
+    )?;
+
+    Ok(order)
+}
+
+fn visit_git_dependencies(
+    lulu: &Lulu,
+    build: bool,
+    runtime: bool,
+    ctx: &Context,
+    stack: &mut Vec<String>,
+    visited: &mut HashSet<String>,
+    order: &mut Vec<GitDependency>,
+) -> Result<(), LuluError> {
+    let outputs = lulu.outputs();
+    let mut dependencies = Vec::<(&String, &Dependency)>::new();
+    if build {
+        dependencies.extend(lulu.dependencies.build.iter());
+    }
+    if runtime {
+        for output in &outputs {
+            dependencies.extend(output.runtime.iter());
+            dependencies.extend(output.optional.iter());
+        }
+    }
+
+    for (name, dependency) in dependencies {
+        if dependency.is != DependencyType::GIT || visited.contains(name) {
+            continue;
+        }
+
+        // Packages of the same recipe are built together
+        if outputs.iter().any(|output| &output.name == name) {
+            continue;
+        }
+
+        if stack.contains(name) {
+            return Err(LuluError::Manifest(format!(
+                "Dependency cycle detected: {} -> {}",
+                stack.join(" -> "),
+                name
+            )));
+        }
+
+        if is_installed(name, ctx)? {
+            trace!("{} is already installed", name);
+            visited.insert(name.to_string());
+            continue;
+        }
+
+        let (path, repository) = match &dependency.git {
+            Some(url) => (fetch_git(url, false)?, None),
+            None => {
+                let (path, repository) = fetch_db(name, None, ctx)?;
+                (path, Some(repository))
             }
         };
-    });
 
-    if status != 0 {
-        error!("Something went wrong");
-        return;
+        let dependency_lulu = read_lulu(&path.join("LULU.toml"))?;
+
+        if &dependency_lulu.package.name != name {
+            warning!(
+                "Dependency {} resolves to package {}",
+                name,
+                dependency_lulu.package.name
+            );
+        }
+
+        stack.push(name.to_string());
+        visit_git_dependencies(
+            &dependency_lulu,
+            !ctx.isolated,
+            true,
+            ctx,
+            stack,
+            visited,
+            order,
+        )?;
+        stack.pop();
+
+        visited.insert(name.to_string());
+        order.push(GitDependency {
+            path,
+            lulu: dependency_lulu,
+            repository,
+        });
+    }
+
+    Ok(())
+}
+
+/// Whether a package is already installed
+///
+/// Without a db (standalone builds), apt is asked instead.
+fn is_installed(name: &str, ctx: &Context) -> Result<bool, LuluError> {
+    match &ctx.db {
+        Some(db) => Ok(db.clone().collection("installed").doc(name).exist),
+        None => Ok(open_cache()?
+            .get(name)
+            .map_or(false, |pkg| pkg.installed().is_some())),
+    }
+}
+
+/// A recipe asked to be installed
+struct Request {
+    /// Directory of the recipe, where it's built
+    path: PathBuf,
+    lulu: Lulu,
+    /// Revision asked on the command line, overrides the one of the package
+    reference: Option<String>,
+    /// Packages of the recipe to install, the main package if empty
+    split: Vec<String>,
+    /// Repository of the recipe, none for git urls and local recipes
+    repository: Option<String>,
+}
+
+/// A recipe whose source is checked out and version known, ready to be built
+struct Prepared {
+    path: PathBuf,
+    lulu: Lulu,
+    reference: Option<String>,
+    split: Vec<String>,
+    repository: Option<String>,
+    repo: Repository,
+    /// Working directory of `repo`
+    srcdir: PathBuf,
+    /// Whether the source directory was cloned for this build
+    cloned: bool,
+    architecture: String,
+    /// Packages built by the recipe with their pkgdir, the main package first
+    outputs: Vec<(Output, PathBuf)>,
+    controls: Vec<Control>,
+    debs: Vec<PathBuf>,
+    version: String,
+    source_date_epoch: String,
+    /// Variables exported to the scripts
+    env: ScriptEnv,
+    git_dependencies: Vec<GitDependency>,
+    /// Git build dependencies built for the build root of an isolated build
+    git_build_dependencies: Vec<GitDependency>,
+}
+
+impl Prepared {
+    /// Built packages to install: the main package unless other packages of the recipe were asked
+    fn to_install(&self) -> Vec<(&Output, &PathBuf)> {
+        self.outputs
+            .iter()
+            .zip(&self.debs)
+            .filter(|((output, _), _)| {
+                if self.split.is_empty() {
+                    output.name == self.lulu.package.name
+                } else {
+                    self.split.contains(&output.name)
+                }
+            })
+            .map(|((output, _), deb)| (output, deb))
+            .collect()
+    }
+}
+
+/// Build and install recipes together
+///
+/// Every recipe is prepared first, then build dependencies of all of them are installed at once,
+/// and built packages are installed in a single transaction.
+fn install_recipes(requests: Vec<Request>, ctx: &mut Context) -> Result<(), LuluError> {
+    let host = match host_architecture() {
+        Some(host) => host,
+        None => {
+            tip!("Is dpkg installed?");
+            return Err(LuluError::Other(
+                "Failed to get host architecture".to_string(),
+            ));
+        }
+    };
+
+    let mut prepared = Vec::<Prepared>::new();
+    for request in requests {
+        if prepared
+            .iter()
+            .any(|p| p.lulu.package.name == request.lulu.package.name)
+        {
+            warning!(
+                "{} is asked several times, it will only be built once",
+                request.lulu.package.name
+            );
+            continue;
+        }
+        set_current_dir(&request.path)?;
+        prepared.push(prepare(request, &host, ctx)?);
+    }
+
+    // Each git dependency is built once, after its own git dependencies
+    let mut git_dependencies = Vec::<GitDependency>::new();
+    for p in prepared.iter_mut() {
+        for dependency in p.git_dependencies.drain(..) {
+            if !git_dependencies
+                .iter()
+                .any(|d| d.lulu.package.name == dependency.lulu.package.name)
+            {
+                git_dependencies.push(dependency);
+            }
+        }
+    }
+    if !git_dependencies.is_empty() && ctx.standalone {
+        let names: Vec<String> = git_dependencies
+            .iter()
+            .map(|d| d.lulu.package.name.clone())
+            .collect();
+        tip!("Install them first with `lulu install {}`", names.join(" "));
+        return Err(LuluError::PackageNotFound(format!(
+            "Missing git build dependencies: {}",
+            names.join(", ")
+        )));
+    }
+
+    show_plan(&prepared, &git_dependencies);
+
+    if !git_dependencies.is_empty() {
+        title!(
+            "🧩",
+            "Installing git dependencies: {}",
+            git_dependencies
+                .iter()
+                .map(|d| d.lulu.package.name.clone())
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        for dependency in git_dependencies {
+            let mut dependency_ctx = Context {
+                no_install: false,
+                standalone: false,
+                output: None,
+                options: BuildOptions::default(),
+                isolated: ctx.isolated,
+                env: ctx.env.clone(),
+                db: ctx.db.clone(),
+            };
+            // An asked package needed to build another one is installed before it
+            match prepared
+                .iter()
+                .position(|p| p.lulu.package.name == dependency.lulu.package.name)
+            {
+                Some(i) => {
+                    let asked = prepared.remove(i);
+                    build_and_install(vec![asked], &mut dependency_ctx)?;
+                }
+                None => {
+                    let request = Request {
+                        path: dependency.path,
+                        lulu: dependency.lulu,
+                        reference: None,
+                        split: vec![],
+                        repository: dependency.repository,
+                    };
+                    install_recipes(vec![request], &mut dependency_ctx)?;
+                }
+            }
+        }
+    }
+
+    for p in &prepared {
+        build_git_build_dependencies(p, ctx)?;
     }
 
-    env::set_current_dir(path.display().to_string()).unwrap();
-    install_local(ctx);
+    build_and_install(prepared, ctx)
 }
 
-fn install_with_ctx(path: PathBuf, lulu: Lulu, ctx: &mut Context) {
+/// Build the git build dependencies of an isolated build into its DEPS directory, from which
+/// they are extracted in its build root
+fn build_git_build_dependencies(p: &Prepared, ctx: &Context) -> Result<(), LuluError> {
+    let output = p.path.join("DEPS");
+    if output.exists() {
+        std::fs::remove_dir_all(&output).map_err(|e| {
+            LuluError::Build(format!("Failed to remove {}: {}", output.display(), e))
+        })?;
+    }
+    if p.git_build_dependencies.is_empty() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(&output)
+        .map_err(|e| LuluError::Build(format!("Failed to create {}: {}", output.display(), e)))?;
+
+    title!(
+        "🧩",
+        "Building git build dependencies of {}",
+        p.lulu.package.name
+    );
+    for dependency in &p.git_build_dependencies {
+        let mut dependency_ctx = Context {
+            no_install: true,
+            standalone: true,
+            output: Some(output.clone()),
+            options: BuildOptions::default(),
+            isolated: true,
+            env: ctx.env.clone(),
+            db: ctx.db.clone(),
+        };
+        let request = Request {
+            path: dependency.path.clone(),
+            lulu: dependency.lulu.clone(),
+            reference: None,
+            split: vec![],
+            repository: dependency.repository.clone(),
+        };
+        install_recipes(vec![request], &mut dependency_ctx)?;
+    }
+    Ok(())
+}
+
+/// Check out the source of a recipe and compute its version
+fn prepare(request: Request, host: &str, ctx: &Context) -> Result<Prepared, LuluError> {
+    let Request {
+        path,
+        lulu,
+        reference,
+        split,
+        repository,
+    } = request;
+
+    let architecture = package_architecture(&lulu.package.arch, host)
+        .map_err(|e| LuluError::Manifest(format!("Can't build {}: {}", lulu.package.name, e)))?;
+    trace!("Building for {}", architecture);
+
+    script_env::validate(&lulu.env)
+        .map_err(|e| LuluError::Manifest(format!("Invalid [env]: {}", e)))?;
+
+    let outputs = lulu.outputs();
+    let controls = outputs
+        .iter()
+        .map(control)
+        .collect::<Result<Vec<Control>, LuluError>>()?;
+    if let Some(priority) = &lulu.package.priority {
+        deb_priority(priority).ok_or(LuluError::Manifest(format!(
+            "Invalid priority {}, expected required, important, standard, optional or extra",
+            priority
+        )))?;
+    }
+    if let Some(section) = &lulu.package.section {
+        if section.is_empty()
+            || !section
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "/-+.".contains(c))
+        {
+            return Err(LuluError::Manifest(format!("Invalid section {}", section)));
+        }
+    }
+    for name in &split {
+        if !outputs.iter().any(|output| &output.name == name) {
+            return Err(LuluError::PackageNotFound(format!(
+                "{} is not a package built by {}",
+                name, lulu.package.name
+            )));
+        }
+    }
+
+    let mut kept = false;
+    let mut cloned = false;
     let repo = match Repository::open(path.clone()) {
         Ok(repo) => repo,
+        Err(_) if ctx.options.keep_srcdir && Repository::open(path.join("SRC")).is_ok() => {
+            title!(
+                "🔎",
+                "Keeping source directory {}",
+                Paint::cyan(path.join("SRC").display()).underline()
+            );
+            kept = true;
+            Repository::open(path.join("SRC"))
+                .map_err(|e| LuluError::Git(format!("Failed to open source repository: {}", e)))?
+        }
         Err(_) => {
+            cloned = true;
             let path2 = path.join("SRC");
-            match Repository::open(path2.clone()) {
-                Ok(repo) => repo,
-                Err(_) => {
-                    let status = fork_wait(|| {
-                        let mut builder = DirBuilder::new();
-                        builder.recursive(true);
-                        builder.create(path2.clone().into_os_string()).unwrap();
-                        title!(
-                            "🔎",
-                            "Cloning source repository into {}",
-                            Paint::cyan(path2.clone().display()).underline()
-                        );
-                        match Repository::clone(&lulu.package.source, path2.clone()) {
-                            Ok(repo) => repo,
-                            Err(e) => {
-                                error!("Failed to clone repository");
-                                panic!("{:?}", e)
-                            }
-                        };
-                    });
-
-                    if status != 0 {
-                        error!("Something went wrong");
-                        panic!("Something went wrong");
-                    }
-
-                    match Repository::open(path2) {
-                        Ok(repo) => repo,
-                        Err(e) => {
-                            panic!("{:?}", e)
-                        }
-                    }
-                }
-            }
+            let mirror = fetch_mirror(&lulu.package.source)?;
+            fork_wait(|| {
+                title!(
+                    "🔎",
+                    "Cloning source repository into {}",
+                    Paint::cyan(path2.clone().display()).underline()
+                );
+                source_cache::checkout(&mirror, &path2)
+                    .map_err(|e| LuluError::Git(format!("Failed to clone repository: {}", e)))?;
+                Ok(())
+            })?;
+
+            Repository::open(path2)
+                .map_err(|e| LuluError::Git(format!("Failed to open source repository: {}", e)))?
         }
     };
 
-    // TODO: Go to a particular commit
+    let srcdir = source_dir(&repo)?;
+
+    let checkout = reference.clone().or_else(|| lulu.package.pinned_ref());
+    // A kept source directory is left as is, local changes included
+    if let Some(checkout) = checkout.as_ref().filter(|_| !kept) {
+        title!("📌", "Checking out {}", Paint::cyan(checkout).italic());
+        fork_wait(|| {
+            let oid = crate::utils::git::checkout(&repo, checkout)
+                .map_err(|e| LuluError::Git(format!("Failed to checkout {}: {}", checkout, e)))?;
+            trace!("HEAD is now at {}", oid);
+            Ok(())
+        })?;
+    }
+
+    verify_source(&repo, checkout.as_deref(), &lulu)?;
+
+    if !lulu.package.sources.is_empty() {
+        title!("📥", "Fetching additional sources");
+        fork_wait(|| fetch_sources(&lulu, &path, &srcdir))?;
+    }
+
+    // Timestamp of the built commit, for reproducible builds
+    let source_date_epoch = repo
+        .head()
+        .and_then(|head| head.peel_to_commit())
+        .map(|commit| commit.time().seconds().to_string())
+        .map_err(|e| LuluError::Git(format!("Failed to read commit time: {}", e)))?;
+
+    // The configuration is exported before the recipe so that the recipe wins
+    let mut env = ScriptEnv::new(&[&ctx.env, &lulu.env]);
+    env.set("pkgname", &lulu.package.name);
+    env.set("CARCH", host);
+    env.set("DEB_HOST_ARCH", host);
+    env.set("SOURCE_COMMIT", &head_commit(&repo)?);
+    env.set("SOURCE_DATE_EPOCH", &source_date_epoch);
+
     let mut version = String::new();
-    if lulu.script.pkgver.is_some() {
-        let status = fork_wait(|| {
+    if let Some(pkgver) = &lulu.script.pkgver {
+        fork_wait(|| {
             let mut version = String::new();
 
-            let srcdir = repo.path().parent().unwrap().to_path_buf();
-
-            let mut child = Command::new("bash")
+            let mut command = Command::new("bash");
+            env.apply(&mut command);
+            let mut child = command
                 .env("srcdir", srcdir.display().to_string())
                 .arg("-ec")
-                .arg(lulu.script.pkgver.clone().unwrap())
+                .arg(env.script(pkgver))
                 .stdout(Stdio::piped())
                 .spawn()
-                .expect("Failed to execute command");
+                .map_err(|e| LuluError::Build(format!("Failed to execute pkgver: {}", e)))?;
 
             if let Some(ref mut stdout) = child.stdout {
                 for line in BufReader::new(stdout).lines() {
-                    let line = line.unwrap();
+                    let line = line
+                        .map_err(|e| LuluError::Build(format!("Failed to read pkgver: {}", e)))?;
                     version.push_str(&line);
                 }
             }
 
-            if !child.wait().unwrap().success() {
-                error!("pkgver failed");
-                std::process::exit(1);
+            if !child.wait().map_or(false, |status| status.success()) {
+                return Err(LuluError::Build("pkgver failed".to_string()));
             }
 
-            let mut output = File::create(".version").unwrap();
-            output.write_fmt(format_args!("{}", version)).unwrap();
-        });
+            let mut output = File::create(state_dir(&srcdir).join("lulu-version"))
+                .map_err(|e| LuluError::Build(format!("Failed to write version: {}", e)))?;
+            output
+                .write_fmt(format_args!("{}", version))
+                .map_err(|e| LuluError::Build(format!("Failed to write version: {}", e)))?;
+            Ok(())
+        })?;
+
+        version.push_str(
+            read_to_string(state_dir(&srcdir).join("lulu-version"))
+                .map_err(|e| LuluError::Build(format!("Failed to read version: {}", e)))?
+                .trim(),
+        );
+        versions::validate(&version).map_err(|e| {
+            LuluError::Manifest(format!("pkgver printed an invalid version: {}", e))
+        })?;
+    } else {
+        version.push_str(&git_version(&repo)?);
+    }
+    let epoch = match &lulu.script.pkgver {
+        Some(_) => lulu.package.epoch,
+        None => Some(lulu.package.epoch.unwrap_or(versions::GIT_EPOCH)),
+    };
+    let version = versions::with_epoch(&version, epoch)
+        .and_then(|version| versions::validate(&version).map(|_| version))
+        .map_err(LuluError::Manifest)?;
 
-        // Verifying if status is ok
-        if status != 0 {
-            error!("Something went wrong");
-            panic!("Something went wrong, status is {status}");
-        }
+    trace!("Version is {}", Paint::cyan(version.clone()));
+    env.set("pkgver", versions::without_epoch(&version));
 
-        version.push_str(&read_to_string(".version").unwrap());
+    // Isolated builds don't install git build dependencies on the host
+    let git_dependencies = resolve_git_dependencies(&lulu, !ctx.isolated, !ctx.no_install, ctx)?;
+    let git_build_dependencies = if ctx.isolated {
+        resolve_git_dependencies(&lulu, true, false, ctx)?
     } else {
-        version.push_str(&match repo.describe(&DescribeOptions::default()) {
-            Ok(d) => match d.format(None) {
-                Ok(s) => s.replace("-", ".").replace("v", ""),
-                Err(e) => {
-                    error!("Failed to get version");
-                    panic!("{:?}", e)
-                }
-            },
-            Err(_) => repo
-                .head()
-                .expect("There should be at least one commit")
-                .target()
-                .expect("The commit should point to a ref")
-                .to_string(),
+        vec![]
+    };
+
+    // The main package goes in LULU and split packages in SPLIT/<name>
+    let outputs: Vec<(Output, PathBuf)> = outputs
+        .into_iter()
+        .map(|output| {
+            let pkgdir = if output.name == lulu.package.name {
+                path.join("LULU")
+            } else {
+                path.join("SPLIT").join(&output.name)
+            };
+            (output, pkgdir)
         })
+        .collect();
+    let debs: Vec<PathBuf> = outputs
+        .iter()
+        .map(|(output, _)| deb_path(&output.name, &version, &path, ctx))
+        .collect();
+
+    Ok(Prepared {
+        path,
+        lulu,
+        reference,
+        split,
+        repository,
+        repo,
+        srcdir,
+        cloned,
+        architecture,
+        outputs,
+        controls,
+        debs,
+        version,
+        source_date_epoch,
+        env,
+        git_dependencies,
+        git_build_dependencies,
+    })
+}
+
+/// Show what is going to be built
+fn show_plan(prepared: &[Prepared], git_dependencies: &[GitDependency]) {
+    title!("📋", "Plan");
+    for dependency in git_dependencies {
+        println!(
+            "{}\t{}",
+            Paint::cyan(&dependency.lulu.package.name).italic(),
+            Paint::yellow("Git dependency, built and installed first")
+        );
+    }
+    for dependency in prepared.iter().flat_map(|p| &p.git_build_dependencies) {
+        println!(
+            "{}\t{}",
+            Paint::cyan(&dependency.lulu.package.name).italic(),
+            Paint::yellow("Git build dependency, built for the build root")
+        );
+    }
+    for p in prepared {
+        println!(
+            "{} {}\t{}",
+            Paint::cyan(&p.lulu.package.name).italic(),
+            p.version,
+            p.outputs
+                .iter()
+                .map(|(output, _)| output.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
     }
+}
 
-    trace!("Version is {}", Paint::cyan(version.clone()));
+/// Build prepared recipes then install the packages they built
+fn build_and_install(prepared: Vec<Prepared>, ctx: &mut Context) -> Result<(), LuluError> {
+    if prepared.is_empty() {
+        return Ok(());
+    }
+
+    // Build dependencies of every recipe are installed on the host at once, isolated builds
+    // install them in their own build root
+    let mut to_uninstall = Vec::<String>::new();
+    if !ctx.isolated {
+        title!("📦", "Installing build dependencies");
+        let cache = open_cache()?;
+        let lulus: Vec<&Lulu> = prepared.iter().map(|p| &p.lulu).collect();
+        let dependencies: Vec<(&String, &Dependency)> = lulus
+            .iter()
+            .flat_map(|lulu| &lulu.dependencies.build)
+            .collect();
+        let (to_install, to_upgrade) = mark_build_dependencies(&cache, &dependencies)?;
+        to_uninstall = to_install;
+
+        // Nothing to do, apt needs root even for an empty transaction
+        if !to_uninstall.is_empty() || !to_upgrade.is_empty() {
+            cache.resolve(true).map_err(|e| {
+                LuluError::Apt(format!("Failed to resolve build dependencies: {}", e))
+            })?;
+
+            let mut acquire_progress = AptAcquireProgress::new_box();
+            let mut install_progress = AptInstallProgress::new_box();
+
+            // Recorded first so that `lulu autoremove` can clean them if lulu doesn't
+            let names: Vec<&str> = lulus.iter().map(|l| l.package.name.as_str()).collect();
+            record_build_dependencies(&to_uninstall, &names.join(", "), ctx)?;
+            cache
+                .get_archives(&mut acquire_progress)
+                .map_err(|e| LuluError::Apt(format!("Failed to download packages: {}", e)))?;
+            cache.do_install(&mut install_progress).map_err(|e| {
+                LuluError::Apt(format!("Failed to install build dependencies: {}", e))
+            })?;
+        }
+    }
+
+    // BUILD
+    let mut status = Ok(());
+    for p in &prepared {
+        status = build_recipe(p, ctx);
+        if status.is_err() {
+            break;
+        }
+    }
 
-    if sudo::check() != sudo::RunningAs::Root {
-        sudo::with_env(&["USER", "HOME"]).expect("lulu need root access to install packages");
+    // Uninstalling
+    if !to_uninstall.is_empty() {
+        title!("📦", "Uninstalling build dependencies");
+        let cache = open_cache()?;
+        let unneeded = unneeded_packages(&cache);
+        let packages: Vec<AptPackage> = to_uninstall
+            .iter()
+            .filter_map(|pkg| cache.get(pkg))
+            .collect();
+        for pkg in packages {
+            pkg.mark_delete(false);
+            pkg.protect();
+        }
+        mark_unneeded(&cache, &unneeded);
+        let mut acquire_progress = AptAcquireProgress::new_box();
+        let mut install_progress = AptInstallProgress::new_box();
+        if let Err(e) = cache.commit(&mut acquire_progress, &mut install_progress) {
+            // The build error is more important than this one
+            status?;
+            return Err(LuluError::Apt(format!(
+                "Failed to uninstall build packages: {}",
+                e
+            )));
+        }
+        if let Err(e) = forget_build_dependencies(&to_uninstall, ctx) {
+            status?;
+            return Err(e);
+        }
     }
+    status?;
 
-    title!("📦", "Installing build dependencies");
-    let apt_dependencies: Vec<String> = lulu
-        .dependencies
-        .build
+    if ctx.standalone {
+        for deb in prepared.iter().flat_map(|p| &p.debs) {
+            success!("Built {}", Paint::cyan(deb.display()).underline());
+        }
+        return Ok(());
+    }
+
+    let to_install: Vec<(&Prepared, &Output, &PathBuf)> = prepared
         .iter()
-        .filter(|(_, e)| e.is == DependencyType::APT)
-        .map(|(k, _)| k.to_string())
+        .flat_map(|p| {
+            p.to_install()
+                .into_iter()
+                .map(move |(output, deb)| (p, output, deb))
+        })
         .collect();
 
-    let cache = match Cache::new::<bool>(&[]) {
-        Ok(c) => c,
-        Err(_) => todo!(),
-    };
+    // Installing built packages
+    if !ctx.no_install {
+        let db = ctx.db()?;
+        with_lock(&db, |db| {
+            let ctx = Context {
+                db: Some(db.clone()),
+                ..ctx.clone()
+            };
+            install_debs(&to_install, &ctx)
+        })?;
+    }
 
+    success!("Done");
+    Ok(())
+}
+
+/// Find apt packages of dependencies and mark the missing ones for install
+///
+/// Installed packages which don't satisfy a versioned relation are marked for upgrade. Return the
+/// names of the packages marked for install, which are removed after the build, and of the ones
+/// marked for upgrade.
+fn mark_build_dependencies(
+    cache: &Cache,
+    dependencies: &[(&String, &Dependency)],
+) -> Result<(Vec<String>, Vec<String>), LuluError> {
     let mut ok = true;
-    for pkg in apt_dependencies.clone() {
-        if cache.get(&pkg).is_none() {
-            warning!("Failed to find package: {}", Paint::yellow(pkg).italic());
-            ok = false;
+    let mut apt_dependencies = Vec::<(AptPackage, Vec<Relation>)>::new();
+    for &(name, dependency) in dependencies {
+        if dependency.is != DependencyType::APT {
+            continue;
+        }
+        let alternatives = alternatives(name, dependency)?;
+        match choose_alternative(cache, &alternatives) {
+            // Recipes built together often share build dependencies, each relation naming
+            // the chosen package has to be satisfied
+            Some(pkg) => {
+                let chosen: Vec<Relation> = alternatives
+                    .into_iter()
+                    .filter(|r| r.name == pkg.name())
+                    .collect();
+                match apt_dependencies
+                    .iter_mut()
+                    .find(|(p, _)| p.name() == pkg.name())
+                {
+                    Some((_, relations)) => relations.extend(chosen),
+                    None => apt_dependencies.push((pkg, chosen)),
+                }
+            }
+            None => {
+                let relation = alternatives
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" | ");
+                if alternatives.iter().any(|r| cache.get(&r.name).is_some()) {
+                    warning!(
+                        "No installable version satisfies {}",
+                        Paint::yellow(relation).italic()
+                    );
+                } else {
+                    warning!(
+                        "Failed to find package: {}",
+                        Paint::yellow(relation).italic()
+                    );
+                }
+                ok = false;
+            }
         }
     }
 
     if !ok {
-        error!("Failed to retreive all packages");
         tip!("Try to run `apt update`");
-        panic!("Some packages don't exist in cache")
+        return Err(LuluError::PackageNotFound(
+            "Failed to retreive all packages".to_string(),
+        ));
     }
 
-    let apt_dependencies: Vec<AptPackage> = apt_dependencies
-        .into_iter()
-        .map(|pkg| cache.get(&pkg).expect("Should be in the cache"))
-        .collect();
-
-    let mut to_uninstall = Vec::<String>::new();
+    let mut to_install = Vec::<String>::new();
+    let mut to_upgrade = Vec::<String>::new();
 
-    for pkg in apt_dependencies {
+    for (pkg, relations) in apt_dependencies {
+        let satisfies = |version: &str| relations.iter().all(|r| r.satisfied_by(version));
+        let installed = pkg.installed().map(|v| v.version().to_string());
+        let satisfied = installed.as_deref().map_or(false, satisfies);
         success!(
             "Found package: {}  \t{}",
             Paint::cyan(pkg.name()).italic(),
-            if pkg.installed().is_some() {
-                Paint::green(format!(
-                    "Installed ({})",
-                    pkg.installed().unwrap().version()
-                ))
-            } else {
-                Paint::red(format!("To be installed"))
+            match &installed {
+                Some(version) if satisfied => Paint::green(format!("Installed ({})", version)),
+                Some(version) => Paint::yellow(format!("Installed ({}), to be upgraded", version)),
+                None => Paint::red(format!("To be installed")),
             }
         );
-        if pkg.installed().is_none() {
-            if !pkg.mark_install(true, false) {
-                error!("Can't mark {} for install", Paint::red(pkg.name()).italic());
-            }
-            pkg.protect();
-            to_uninstall.push(pkg.name().to_string())
+        if satisfied {
+            continue;
         }
-    }
-
-    cache.resolve(true).unwrap();
-
-    let mut acquire_progress = AptAcquireProgress::new_box();
-    let mut install_progress = AptInstallProgress::new_box();
 
-    match cache.get_archives(&mut acquire_progress) {
-        Ok(_) => match cache.do_install(&mut install_progress) {
-            Ok(_) => (),
-            Err(e) => panic!("{:?}", e),
-        },
-        Err(e) => panic!("{:?}", e),
+        let candidate = pkg.candidate().map(|v| v.version().to_string());
+        if !candidate.as_deref().map_or(false, satisfies) {
+            return Err(LuluError::PackageNotFound(format!(
+                "No installable version of {} satisfies {}",
+                pkg.name(),
+                relations
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )));
+        }
+        if !pkg.mark_install(true, false) {
+            warning!("Can't mark {} for install", Paint::red(pkg.name()).italic());
+        }
+        pkg.protect();
+        // Upgraded packages were there before the build and stay installed
+        match installed {
+            Some(_) => to_upgrade.push(pkg.name().to_string()),
+            None => to_install.push(pkg.name().to_string()),
+        }
     }
 
-    // BUILD
-
-    let status = fork_wait(|| {
-        let srcdir = repo.path().parent().unwrap().to_path_buf();
-
-        let pkgdir = path.join("LULU");
-        let mut builder = DirBuilder::new();
-        builder.recursive(true);
-        builder.create(pkgdir.clone().into_os_string()).unwrap();
+    let missing = [to_install.as_slice(), to_upgrade.as_slice()].concat();
+    if !missing.is_empty() && sudo::check() != sudo::RunningAs::Root {
+        tip!(
+            "Install them with `sudo apt install {}` or run lulu as root",
+            missing.join(" ")
+        );
+        return Err(LuluError::Permission(format!(
+            "Missing build dependencies: {}",
+            missing.join(", ")
+        )));
+    }
 
-        generate(lulu.clone(), path.clone(), srcdir, pkgdir.clone());
+    Ok((to_install, to_upgrade))
+}
 
-        let mut package = DebPackage::new(&lulu.package.name);
-        let provides: Vec<&str> = lulu.package.provides.iter().map(String::as_str).collect();
-        let dependencies_runtime: Vec<&str> = lulu
-            .dependencies
-            .runtime
+/// Create the build root of a recipe and extract its build dependencies in it
+fn build_root(p: &Prepared) -> Result<BuildRoot, LuluError> {
+    let root = BuildRoot::new(
+        p.path.join("ROOT"),
+        vec![p.path.clone(), p.srcdir.clone(), p.path.join("LULU")],
+    )
+    .map_err(|e| LuluError::Build(format!("Failed to create build root: {}", e)))?;
+
+    title!("📦", "Installing build dependencies in build root");
+    let result = open_cache().and_then(|cache| {
+        // Git build dependencies need their own apt dependencies
+        let outputs: Vec<Output> = p
+            .git_build_dependencies
             .iter()
-            .map(|d| d.0.as_str())
+            .flat_map(|dependency| dependency.lulu.outputs())
             .collect();
-        let dependencies_optional: Vec<&str> = lulu
-            .dependencies
-            .optional
-            .iter()
-            .map(|d| d.0.as_str())
+        let mut dependencies: Vec<(&String, &Dependency)> =
+            p.lulu.dependencies.build.iter().collect();
+        dependencies.extend(
+            outputs
+                .iter()
+                .flat_map(|output| output.pre.iter().chain(&output.runtime)),
+        );
+        mark_build_dependencies(&cache, &dependencies)?;
+        cache
+            .resolve(true)
+            .map_err(|e| LuluError::Apt(format!("Failed to resolve build dependencies: {}", e)))?;
+
+        // Packages are only downloaded then extracted in the build root
+        let mut archives: Vec<PathBuf> = cache
+            .get_changes(false)
+            .filter_map(|pkg| {
+                pkg.candidate()
+                    .map(|v| archive_path(pkg.name(), v.version(), v.arch()))
+            })
             .collect();
-
-        package = package
-            .set_version(&version)
-            .set_description(&lulu.package.description)
-            .set_architecture(DebArchitecture::Amd64)
-            .set_maintainer(
-                lulu.package
-                    .maintainers
-                    .first()
-                    .expect("There should be at least one maintener"),
-            )
-            .with_provides(provides)
-            .with_depends(dependencies_runtime)
-            .with_recommends(dependencies_optional);
-
-        if lulu.package.preinst.is_some() {
-            package = package.preinst_from_str(&lulu.package.preinst.clone().unwrap());
+        let mut acquire_progress = AptAcquireProgress::new_box();
+        cache
+            .get_archives(&mut acquire_progress)
+            .map_err(|e| LuluError::Apt(format!("Failed to download packages: {}", e)))?;
+        if let Ok(entries) = std::fs::read_dir(p.path.join("DEPS")) {
+            archives.extend(
+                entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().map_or(false, |e| e == "deb")),
+            );
         }
-
-        if lulu.package.postinst.is_some() {
-            package = package.postinst_from_str(&lulu.package.postinst.clone().unwrap());
+        for archive in archives {
+            trace!("Extracting {}", archive.display());
+            root.extract(&archive).map_err(|e| {
+                LuluError::Build(format!(
+                    "Failed to extract {} in build root: {}",
+                    archive.display(),
+                    e
+                ))
+            })?;
         }
+        root.merge_usr()
+            .map_err(|e| LuluError::Build(format!("Failed to prepare build root: {}", e)))
+    });
 
-        if lulu.package.prerm.is_some() {
-            package = package.prerm_from_str(&lulu.package.prerm.clone().unwrap());
+    if let Err(e) = result {
+        if root.remove().is_err() {
+            warning!("Failed to remove build root");
         }
+        return Err(e);
+    }
+    Ok(root)
+}
 
-        if lulu.package.postrm.is_some() {
-            package = package.postrm_from_str(&lulu.package.postrm.clone().unwrap());
-        }
+/// Run the scripts of a prepared recipe and write its packages
+fn build_recipe(p: &Prepared, ctx: &Context) -> Result<(), LuluError> {
+    set_current_dir(&p.path)?;
+    title!(
+        "⚙",
+        "{} {}",
+        Paint::cyan(&p.lulu.package.name).italic(),
+        p.version
+    );
 
-        package = package
-            .with_dir(pkgdir, std::path::Path::new("").to_path_buf())
-            .unwrap();
+    // A fresh clone has nothing built, starting later would package an unbuilt tree
+    if p.cloned {
+        if let Some(phase) = &ctx.options.from_phase {
+            return Err(LuluError::Build(format!(
+                "No previous build of {} to resume from {}",
+                p.lulu.package.name, phase
+            )));
+        }
+    }
 
-        package
-            .build()
-            .unwrap()
-            .write(File::create(format!("{}-{}.deb", lulu.package.name, version)).unwrap())
-            .unwrap();
-    });
+    let root = if ctx.isolated {
+        Some(build_root(p)?)
+    } else {
+        None
+    };
 
-    // Uninstalling
-    title!("📦", "Uninstalling build dependencies");
-    let cache = match Cache::new::<bool>(&[]) {
-        Ok(c) => c,
-        Err(_) => todo!(),
+    // Phases completed by a previous build are only trusted when its source directory was kept
+    if p.cloned || (ctx.options.from_phase.is_none() && !ctx.options.keep_srcdir) {
+        reset_phases(&p.srcdir)?;
+    }
+    let completed = completed_phases(&p.srcdir);
+    let start = match &ctx.options.from_phase {
+        Some(phase) => phase.to_string(),
+        None => PHASES
+            .iter()
+            .find(|phase| !completed.contains(&phase.to_string()))
+            .unwrap_or(&"package")
+            .to_string(),
     };
-    let to_uninstall: Vec<AptPackage> = to_uninstall
-        .into_iter()
-        .map(|pkg| cache.get(&pkg).expect("Should be in the cache"))
-        .collect();
-    for pkg in to_uninstall {
-        pkg.mark_delete(false);
-        pkg.protect();
+    if start != "prepare" {
+        title!("⏩", "Resuming build from {}", Paint::cyan(&start).italic());
+        for phase in PHASES.iter().take_while(|phase| **phase != start) {
+            if !completed.contains(&phase.to_string()) {
+                warning!("Phase {} didn't complete in a previous build", phase);
+            }
+        }
     }
-    let mut acquire_progress = AptAcquireProgress::new_box();
-    let mut install_progress = AptInstallProgress::new_box();
-    match cache.commit(&mut acquire_progress, &mut install_progress) {
-        Ok(_) => {}
-        Err(_) => {
-            error!("Failed to uninstall build packages");
-            panic!("Failed to uninstall build packages");
+    let options = BuildOptions {
+        from_phase: Some(start),
+        ..ctx.options.clone()
+    };
+
+    let log = match BuildLog::new(&p.lulu.package.name) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warning!("Failed to create build log directory, the build won't be logged");
+            eprintln!("{:?}", e);
+            None
         }
     };
 
-    // Verifying if status is ok
-    if status != 0 {
-        error!("Something went wrong");
-        panic!("Something went wrong");
+    let status = fork_wait(|| {
+        for (_, pkgdir) in &p.outputs {
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            builder
+                .create(pkgdir.clone().into_os_string())
+                .map_err(|e| LuluError::Build(format!("Failed to create pkgdir: {}", e)))?;
+        }
+
+        generate(
+            p.lulu.clone(),
+            p.path.clone(),
+            p.srcdir.clone(),
+            &p.outputs,
+            root.as_ref(),
+            log.as_ref(),
+            &options,
+            &p.env,
+        )?;
+
+        let maintainer = p
+            .lulu
+            .package
+            .maintainers
+            .first()
+            .ok_or(LuluError::Manifest(
+                "There should be at least one maintener".to_string(),
+            ))?;
+        for (((output, pkgdir), control), deb) in p.outputs.iter().zip(&p.controls).zip(&p.debs) {
+            write_deb(
+                output,
+                control,
+                &p.lulu.package,
+                pkgdir,
+                deb,
+                &p.version,
+                &p.architecture,
+                maintainer,
+                &p.source_date_epoch,
+            )?;
+        }
+        Ok(())
+    });
+
+    if let Some(root) = root {
+        title!("📦", "Removing build root");
+        if let Err(e) = root.remove() {
+            warning!("Failed to remove build root");
+            eprintln!("{:?}", e);
+        }
     }
 
-    // Installing built package
-    match ctx.db.lock() {
-        Ok(_) => {}
-        Err(_) => {
-            error!("Failed to lock database");
-            panic!("Failed to lock database");
+    if status.is_err() {
+        if let Some(log) = &log {
+            tip!("Logs of the build are in {}", log.path.display());
         }
     }
+    status
+}
 
-    if !ctx.no_install {
-        title!(
-            "📦",
-            "Installing {}",
-            Paint::cyan(lulu.package.name.clone()).italic()
-        );
-        let cache = match Cache::new::<&str>(&[Path::new(&format!(
-            "{}-{}.deb",
-            lulu.package.name, version
-        ))
-        .to_str()
-        .expect("Path should exist")])
-        {
-            Ok(c) => c,
-            Err(_) => todo!(),
-        };
-        let package = match cache.get(&lulu.package.name) {
+/// Install built packages in a single transaction and record them in the db
+fn install_debs(debs: &[(&Prepared, &Output, &PathBuf)], ctx: &Context) -> Result<(), LuluError> {
+    let names: Vec<&str> = debs
+        .iter()
+        .map(|(_, output, _)| output.name.as_str())
+        .collect();
+    title!(
+        "📦",
+        "Installing {}",
+        Paint::cyan(names.join(", ")).italic()
+    );
+    let paths: Vec<String> = debs
+        .iter()
+        .map(|(_, _, deb)| deb.display().to_string())
+        .collect();
+    let cache = Cache::new::<&str>(&paths.iter().map(String::as_str).collect::<Vec<&str>>())
+        .map_err(|e| LuluError::Apt(format!("Failed to open apt cache: {}", e)))?;
+    for ((_, output, _), deb) in debs.iter().zip(&paths) {
+        let package = match cache.get(&output.name) {
             Some(p) => p,
             None => {
-                error!("Package not found");
-                panic!("Package not found");
+                return Err(LuluError::PackageNotFound(format!(
+                    "Package {} not found in {}",
+                    output.name, deb
+                )))
             }
         };
 
         println!(
-            "{}",
+            "{}\t{}",
+            output.name,
             package
                 .installed()
                 .map_or("Not installed".to_string(), |v| v.version().to_string())
         );
         package.mark_install(true, true);
         package.protect();
+    }
 
-        cache.resolve(true).unwrap();
+    cache
+        .resolve(true)
+        .map_err(|e| LuluError::Apt(format!("Failed to resolve dependencies: {}", e)))?;
 
-        let mut acquire_progress = AptAcquireProgress::new_box();
-        let mut install_progress = AptInstallProgress::new_box();
+    let mut acquire_progress = AptAcquireProgress::new_box();
+    let mut install_progress = AptInstallProgress::new_box();
 
-        match cache.get_archives(&mut acquire_progress) {
-            Ok(_) => match cache.do_install(&mut install_progress) {
-                Ok(_) => (),
-                Err(e) => panic!("{:?}", e),
-            },
-            Err(e) => panic!("{:?}", e),
-        }
+    cache
+        .get_archives(&mut acquire_progress)
+        .map_err(|e| LuluError::Apt(format!("Failed to download packages: {}", e)))?;
+    cache
+        .do_install(&mut install_progress)
+        .map_err(|e| LuluError::Apt(format!("Failed to install {}: {}", paths.join(", "), e)))?;
 
-        match ctx
-            .db
+    for (p, output, _) in debs {
+        ctx.db()?
             .clone()
             .collection("installed")
-            .doc(lulu.package.name.as_str())
+            .doc(output.name.as_str())
             .set(Installed {
-                version,
-                hash: repo
-                    .head()
-                    .expect("There should be at least one commit")
-                    .target()
-                    .expect("The commit should point to a ref")
-                    .to_string(),
-                source: lulu.package.source,
-            }) {
-            Ok(_) => {}
-            Err(e) => {
-                panic!("{:?}", e);
-            }
-        };
+                version: p.version.clone(),
+                hash: head_commit(&p.repo)?,
+                source: p.lulu.package.source.clone(),
+                pinned: p.reference.clone(),
+                repository: p.repository.clone(),
+                recipe: if output.name == p.lulu.package.name {
+                    None
+                } else {
+                    Some(p.lulu.package.name.clone())
+                },
+            })
+            .map_err(|e| LuluError::Db(format!("Failed to record {}: {}", output.name, e)))?;
+    }
+    Ok(())
+}
+
+/// Alternatives of a dependency, the dependency itself first
+fn alternatives(name: &str, dependency: &Dependency) -> Result<Vec<Relation>, LuluError> {
+    let mut alternatives = vec![Relation::new(name, dependency.version.as_deref())];
+    alternatives.extend(dependency.alternatives.iter().map(|a| Relation::parse(a)));
+    alternatives
+        .into_iter()
+        .collect::<Result<Vec<Relation>, String>>()
+        .map_err(|e| LuluError::Manifest(format!("Invalid dependency {}: {}", name, e)))
+}
+
+/// First alternative which is installed, otherwise the first one which can be installed
+fn choose_alternative<'a>(cache: &'a Cache, alternatives: &[Relation]) -> Option<AptPackage<'a>> {
+    let installed = alternatives.iter().find_map(|relation| {
+        let pkg = cache.get(&relation.name)?;
+        let satisfied = pkg
+            .installed()
+            .map_or(false, |v| relation.satisfied_by(v.version()));
+        satisfied.then_some(pkg)
+    });
+    installed.or_else(|| {
+        alternatives.iter().find_map(|relation| {
+            let pkg = cache.get(&relation.name)?;
+            let satisfied = pkg
+                .candidate()
+                .map_or(false, |v| relation.satisfied_by(v.version()));
+            satisfied.then_some(pkg)
+        })
+    })
+}
+
+/// Relation fields of the control file of a package
+struct Control {
+    pre_depends: Vec<String>,
+    depends: Vec<String>,
+    recommends: Vec<String>,
+    suggests: Vec<String>,
+    conflicts: Vec<String>,
+    replaces: Vec<String>,
+    breaks: Vec<String>,
+}
+
+fn control(output: &Output) -> Result<Control, LuluError> {
+    let dependencies = |dependencies: &BTreeMap<String, Dependency>| {
+        dependencies
+            .iter()
+            .map(|(name, dependency)| {
+                Ok(alternatives(name, dependency)?
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" | "))
+            })
+            .collect::<Result<Vec<String>, LuluError>>()
+    };
+    let relations = |relations: &[String]| {
+        relations
+            .iter()
+            .map(|relation| {
+                relation
+                    .split('|')
+                    .map(|r| Relation::parse(r).map(|r| r.to_string()))
+                    .collect::<Result<Vec<String>, String>>()
+                    .map(|alternatives| alternatives.join(" | "))
+                    .map_err(|e| {
+                        LuluError::Manifest(format!("Invalid relation in {}: {}", output.name, e))
+                    })
+            })
+            .collect::<Result<Vec<String>, LuluError>>()
+    };
+
+    Ok(Control {
+        pre_depends: dependencies(&output.pre)?,
+        depends: dependencies(&output.runtime)?,
+        recommends: dependencies(&output.optional)?,
+        suggests: relations(&output.suggests)?,
+        conflicts: relations(&output.conflicts)?,
+        replaces: relations(&output.replaces)?,
+        breaks: relations(&output.breaks)?,
+    })
+}
+
+fn deb_priority(priority: &str) -> Option<DebPriority> {
+    match priority {
+        "required" => Some(DebPriority::Required),
+        "important" => Some(DebPriority::Important),
+        "standard" => Some(DebPriority::Standard),
+        "optional" => Some(DebPriority::Optional),
+        "extra" => Some(DebPriority::Extra),
+        _ => None,
+    }
+}
+
+/// Write the .deb of a package produced by the recipe
+#[allow(clippy::too_many_arguments)]
+fn write_deb(
+    output: &Output,
+    control: &Control,
+    recipe: &Package,
+    pkgdir: &Path,
+    deb: &Path,
+    version: &str,
+    architecture: &str,
+    maintainer: &str,
+    source_date_epoch: &str,
+) -> Result<(), LuluError> {
+    let mut package = DebPackage::new(&output.name);
+    package =
+        package
+            .set_version(version)
+            .set_description(&output.description)
+            .set_architecture(deb_architecture(architecture).ok_or(LuluError::Manifest(
+                format!("Unsupported architecture {}", architecture),
+            ))?)
+            .set_maintainer(maintainer)
+            .with_provides(output.provides.iter().map(String::as_str).collect())
+            .with_pre_depends(control.pre_depends.iter().map(String::as_str).collect())
+            .with_depends(control.depends.iter().map(String::as_str).collect())
+            .with_recommends(control.recommends.iter().map(String::as_str).collect())
+            .with_suggests(control.suggests.iter().map(String::as_str).collect())
+            .with_conflicts(control.conflicts.iter().map(String::as_str).collect())
+            .with_replaces(control.replaces.iter().map(String::as_str).collect())
+            .with_breaks(control.breaks.iter().map(String::as_str).collect());
+
+    if let Some(priority) = &recipe.priority {
+        package = package.set_priority(deb_priority(priority).ok_or(LuluError::Manifest(
+            format!("Invalid priority {}", priority),
+        ))?);
+    }
+
+    if let Some(url) = &recipe.url {
+        package = package.set_homepage(url);
+    }
+
+    if let Some(preinst) = &output.preinst {
+        package = package.preinst_from_str(preinst);
+    }
+
+    if let Some(postinst) = &output.postinst {
+        package = package.postinst_from_str(postinst);
+    }
+
+    if let Some(prerm) = &output.prerm {
+        package = package.prerm_from_str(prerm);
+    }
+
+    if let Some(postrm) = &output.postrm {
+        package = package.postrm_from_str(postrm);
     }
 
-    match ctx.db.unlock() {
-        Ok(_) => {}
+    let files = package_files(pkgdir, Path::new(""))
+        .map_err(|e| LuluError::Build(format!("Failed to list files of package: {}", e)))?;
+    let conffiles = conffiles(output, &files)?;
+    for file in files {
+        let path = pkgdir.join(&file);
+        let dest = Path::new("/").join(&file);
+        let mode = std::fs::metadata(&path)
+            .map_err(|e| LuluError::Build(format!("Failed to read {}: {}", path.display(), e)))?
+            .permissions()
+            .mode();
+        let deb_file = DebFile::from_path(&path, &dest)
+            .map_err(|e| LuluError::Build(format!("Failed to add {}: {}", path.display(), e)))?
+            .set_mode(mode & 0o7777)
+            .is_conf(conffiles.contains(&dest));
+        package = package.with_file(deb_file);
+    }
+
+    let file = File::create(deb)
+        .map_err(|e| LuluError::Build(format!("Failed to create package: {}", e)))?;
+    package
+        .build()
+        .and_then(|archive| archive.write(file))
+        .map_err(|e| LuluError::Build(format!("Failed to build package: {}", e)))?;
+
+    normalize_deb(deb, recipe.section.as_deref(), source_date_epoch)
+}
+
+/// Files of a pkgdir relative to it, sorted
+fn package_files(pkgdir: &Path, relative: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(pkgdir.join(relative))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(package_files(pkgdir, &path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Conffiles of a package: files under /etc and the ones listed in `conffiles`, except the ones
+/// listed in `noconffiles`
+fn conffiles(output: &Output, files: &[PathBuf]) -> Result<Vec<PathBuf>, LuluError> {
+    let absolute = |list: &[String]| {
+        list.iter()
+            .map(|file| {
+                if Path::new(file).is_absolute() {
+                    Ok(PathBuf::from(file))
+                } else {
+                    Err(LuluError::Manifest(format!(
+                        "Conffile {} of {} should be an absolute path",
+                        file, output.name
+                    )))
+                }
+            })
+            .collect::<Result<Vec<PathBuf>, LuluError>>()
+    };
+    let listed = absolute(&output.conffiles)?;
+    let excluded = absolute(&output.noconffiles)?;
+
+    let files: Vec<PathBuf> = files.iter().map(|f| Path::new("/").join(f)).collect();
+    for file in &listed {
+        if !files.contains(file) {
+            return Err(LuluError::Build(format!(
+                "Conffile {} is not in the package {}",
+                file.display(),
+                output.name
+            )));
+        }
+    }
+
+    let conffiles: Vec<PathBuf> = files
+        .into_iter()
+        .filter(|file| file.starts_with("/etc") || listed.contains(file))
+        .filter(|file| !excluded.contains(file))
+        .collect();
+    trace!("Conffiles of {}: {:?}", output.name, conffiles);
+    Ok(conffiles)
+}
+
+/// Unpack a built package and pack it again with dpkg-deb so that it is reproducible
+///
+/// dpkg-deb sorts members, clamps mtimes to SOURCE_DATE_EPOCH and, with `--root-owner-group`,
+/// makes every file owned by root. The Section field, which deb-rust has no setter for, is added
+/// on the way.
+fn normalize_deb(
+    deb: &Path,
+    section: Option<&str>,
+    source_date_epoch: &str,
+) -> Result<(), LuluError> {
+    let dir = deb.with_extension("unpacked");
+    let status = Command::new("fakeroot")
+        .arg("--")
+        .arg("bash")
+        .arg("-ec")
+        .arg(
+            r#"rm -rf "$1"
+dpkg-deb -R "$2" "$1"
+if [ -n "$3" ]; then sed -i -e "/^Section:/d" -e "/^Package:/a Section: $3" "$1/DEBIAN/control"; fi
+dpkg-deb --root-owner-group -b "$1" "$2" >/dev/null
+rm -rf "$1""#,
+        )
+        .arg("lulu")
+        .arg(&dir)
+        .arg(deb)
+        .arg(section.unwrap_or_default())
+        .env("SOURCE_DATE_EPOCH", source_date_epoch)
+        .status()
+        .map_err(|e| LuluError::Build(format!("Failed to execute dpkg-deb: {}", e)))?;
+    if !status.success() {
+        return Err(LuluError::Build(format!(
+            "Failed to normalize {}",
+            deb.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Verify the signature of the tag or commit which is built
+///
+/// Verification is required when the package lists `validpgpkeys` or when `required` is set in the
+/// `[verify]` section of /etc/lulu.conf, otherwise a bad signature is only a warning.
+fn verify_source(repo: &Repository, reference: Option<&str>, lulu: &Lulu) -> Result<(), LuluError> {
+    let config = if Path::new(CONFIG_PATH).is_file() {
+        read_config()?.verify
+    } else {
+        VerifyConfig::default()
+    };
+    let validpgpkeys: Vec<String> = lulu
+        .package
+        .validpgpkeys
+        .iter()
+        .map(|key| normalize_fingerprint(key))
+        .collect();
+    let required = config.required || !validpgpkeys.is_empty();
+    let keyring = PathBuf::from(config.keyring.unwrap_or(KEYRING_PATH.to_string()));
+
+    let signature = find_signature(repo, reference)
+        .map_err(|e| LuluError::Git(format!("Failed to read signature: {}", e)))?;
+    let signature = match signature {
+        Some(signature) => signature,
+        None if required => {
+            return Err(LuluError::Verification(format!(
+                "Source of {} is not signed",
+                lulu.package.name
+            )))
+        }
+        None => {
+            trace!("Source of {} is not signed", lulu.package.name);
+            return Ok(());
+        }
+    };
+
+    title!("🔏", "Verifying signature of {}", signature.object);
+    if !keyring.is_file() {
+        if required {
+            tip!(
+                "Import trusted keys with `gpg --no-default-keyring --keyring {} --import`",
+                keyring.display()
+            );
+            return Err(LuluError::Config(format!(
+                "Keyring {} not found",
+                keyring.display()
+            )));
+        }
+        warning!(
+            "Keyring {} not found, signature not verified",
+            keyring.display()
+        );
+        return Ok(());
+    }
+
+    let fingerprints = match verify(&signature, &keyring) {
+        Ok(fingerprints) => fingerprints,
+        Err(e) if required => return Err(LuluError::Verification(e)),
         Err(e) => {
-            error!("Failed to unlock database");
-            panic!("{:?}", e);
+            warning!("{}", e);
+            return Ok(());
         }
     };
 
-    success!("Done");
+    if !validpgpkeys.is_empty()
+        && !fingerprints
+            .iter()
+            .any(|fingerprint| validpgpkeys.contains(&normalize_fingerprint(fingerprint)))
+    {
+        return Err(LuluError::Verification(format!(
+            "{} is signed by {} which is not in validpgpkeys",
+            signature.object,
+            fingerprints.first().map_or("", String::as_str)
+        )));
+    }
+
+    success!(
+        "Good signature by {}",
+        Paint::cyan(fingerprints.last().map_or("", String::as_str))
+    );
+    Ok(())
+}
+
+/// Copy or download the additional sources of a package into `srcdir` and verify their checksums
+fn fetch_sources(lulu: &Lulu, recipe_dir: &Path, srcdir: &Path) -> Result<(), LuluError> {
+    for source in &lulu.package.sources {
+        let dest = srcdir.join(sources::destination(source).map_err(LuluError::Manifest)?);
+        sources::fetch(&source.url, recipe_dir, &dest).map_err(LuluError::Build)?;
+
+        let sum = sources::sha256(&dest).map_err(LuluError::Build)?;
+        if sum != source.sha256.to_lowercase() {
+            let _ = std::fs::remove_file(&dest);
+            return Err(LuluError::Verification(format!(
+                "Checksum of {} doesn't match: expected {}, got {}",
+                source.url, source.sha256, sum
+            )));
+        }
+        success!(
+            "{}\t{}",
+            Paint::cyan(&source.url).italic(),
+            Paint::green("Checksum ok")
+        );
+    }
+    Ok(())
+}
+
+/// Where the package `name` is written, the recipe directory `path` without output directory
+///
+/// Paths are absolute since the current directory changes with each recipe built.
+fn deb_path(name: &str, version: &str, path: &Path, ctx: &Context) -> PathBuf {
+    let file = format!("{}-{}.deb", name, versions::without_epoch(version));
+    match &ctx.output {
+        Some(output) => output.join(file),
+        None => path.join(file),
+    }
+}
+
+/// Version of the commit pointed by HEAD, from the last tag before it if any
+fn git_version(repo: &Repository) -> Result<String, LuluError> {
+    let head = head_commit(repo)?;
+    // Lightweight tags are used by most projects
+    let described = repo
+        .describe(DescribeOptions::new().describe_tags())
+        .and_then(|d| {
+            d.format(Some(
+                DescribeFormatOptions::new()
+                    .always_use_long_format(true)
+                    .abbreviated_size(40),
+            ))
+        });
+    // Long format is `<tag>-<count>-g<hash>`
+    let tagged = described.ok().and_then(|described| {
+        let mut parts = described.rsplitn(3, '-');
+        let _hash = parts.next()?;
+        let count = parts.next()?.parse::<usize>().ok()?;
+        let tag = parts.next()?;
+        versions::from_tag(tag, count, &head)
+    });
+
+    match tagged {
+        Some(version) => Ok(version),
+        None => {
+            let time = repo
+                .head()
+                .and_then(|head| head.peel_to_commit())
+                .map(|commit| commit.time().seconds())
+                .map_err(|e| LuluError::Git(format!("Failed to read commit time: {}", e)))?;
+            Ok(versions::from_commit(time, &head))
+        }
+    }
+}
+
+/// Commit pointed by HEAD
+fn head_commit(repo: &Repository) -> Result<String, LuluError> {
+    repo.head()
+        .ok()
+        .and_then(|head| head.target())
+        .map(|oid| oid.to_string())
+        .ok_or(LuluError::Git(
+            "There should be at least one commit".to_string(),
+        ))
+}
+
+/// Record build dependencies installed for `package` before installing them
+fn record_build_dependencies(
+    names: &[String],
+    package: &str,
+    ctx: &Context,
+) -> Result<(), LuluError> {
+    let db = match &ctx.db {
+        Some(db) if !names.is_empty() => db,
+        _ => return Ok(()),
+    };
+    let pid = unsafe { libc::getpid() };
+    let started = start_id(pid);
+    with_lock(db, |db| {
+        names.iter().try_for_each(|name| {
+            db.clone()
+                .collection("build_dependencies")
+                .doc(name)
+                .set(BuildDependency {
+                    package: package.to_string(),
+                    pid,
+                    started: started.clone(),
+                })
+                .map_err(|e| {
+                    LuluError::Db(format!("Failed to record build dependency {}: {}", name, e))
+                })
+        })
+    })
+}
+
+/// Forget build dependencies once they are uninstalled
+fn forget_build_dependencies(names: &[String], ctx: &Context) -> Result<(), LuluError> {
+    let db = match &ctx.db {
+        Some(db) if !names.is_empty() => db,
+        _ => return Ok(()),
+    };
+    with_lock(db, |db| {
+        names.iter().try_for_each(|name| {
+            let mut document = db.clone().collection("build_dependencies").doc(name);
+            if !document.exist {
+                return Ok(());
+            }
+            document.delete().map_err(|e| {
+                LuluError::Db(format!("Failed to forget build dependency {}: {}", name, e))
+            })
+        })
+    })
+}
+
+fn open_cache() -> Result<Cache, LuluError> {
+    Cache::new::<bool>(&[]).map_err(|e| LuluError::Apt(format!("Failed to open apt cache: {}", e)))
 }
 
-fn generate(lulu: Lulu, basedir: PathBuf, srcdir: PathBuf, pkgdir: PathBuf) {
-    let bash_command = |script: String| {
-        Command::new("bash")
+/// Run the scripts of a recipe
+///
+/// `env` holds variables exported to the scripts in addition to basedir, srcdir and pkgdir.
+#[allow(clippy::too_many_arguments)]
+fn generate(
+    lulu: Lulu,
+    basedir: PathBuf,
+    srcdir: PathBuf,
+    outputs: &[(Output, PathBuf)],
+    root: Option<&BuildRoot>,
+    log: Option<&BuildLog>,
+    options: &BuildOptions,
+    env: &ScriptEnv,
+) -> Result<(), LuluError> {
+    let pkgdir = &outputs.first().expect("There should be a main package").1;
+    let start = PHASES
+        .iter()
+        .position(|phase| Some(phase.to_string()) == options.from_phase)
+        .unwrap_or(0);
+    let should_run = |phase: &str| PHASES.iter().position(|p| *p == phase).unwrap_or(0) >= start;
+
+    let command = |program: &str| match root {
+        Some(root) => root.command(program),
+        None => Command::new(program),
+    };
+
+    let run = |mut command: Command,
+               phase: &str,
+               log_name: &str,
+               pkgdir: &Path|
+     -> Result<(), LuluError> {
+        command
             .env("basedir", basedir.display().to_string())
             .env("srcdir", srcdir.display().to_string())
-            .env("pkgdir", pkgdir.display().to_string())
-            .arg("-ec")
-            .arg(script)
-            .spawn()
-            .expect("Failed to execute command")
-            .wait()
-            .unwrap()
-            .success()
+            .env("pkgdir", pkgdir.display().to_string());
+        env.apply(&mut command);
+        let status = match log {
+            Some(log) => log.run(&mut command, log_name),
+            None => command.status(),
+        }
+        .map_err(|e| LuluError::Build(format!("Failed to execute {}: {}", phase, e)))?;
+        if !status.success() {
+            return Err(LuluError::Build(format!("{} failed", phase)));
+        }
+        Ok(())
+    };
+
+    let bash_command = |script: String, phase: &str, log_name: &str| {
+        let mut bash = command("bash");
+        bash.arg("-ec").arg(env.script(&script));
+        run(bash, phase, log_name, pkgdir)
     };
 
     // Prepare
-    title!("🔧", "Preparing");
-    if lulu.script.prepare.is_some() {
-        if !bash_command(lulu.script.prepare.unwrap()) {
-            error!("Prepare failed");
-            std::process::exit(1);
+    if should_run("prepare") && !options.no_prepare {
+        title!("🔧", "Preparing");
+        if let Some(prepare) = lulu.script.prepare {
+            bash_command(prepare, "Prepare", "prepare")?;
         }
+        record_phase(&srcdir, "prepare")?;
     }
 
     // Build
-    title!("🔨", "Building");
-    env::set_current_dir(srcdir.display().to_string()).unwrap();
-    if lulu.script.build.is_some() {
-        if !bash_command(lulu.script.build.unwrap()) {
-            error!("Build failed");
-            std::process::exit(1);
+    set_current_dir(&srcdir)?;
+    if should_run("build") {
+        title!("🔨", "Building");
+        if let Some(build) = lulu.script.build {
+            bash_command(build, "Build", "build")?;
         }
+        record_phase(&srcdir, "build")?;
     }
 
     // Test
-    title!("🪃", "Testing");
-    if lulu.script.check.is_some() {
-        if !bash_command(lulu.script.check.unwrap()) {
-            error!("Test failed");
-            std::process::exit(1);
+    if should_run("check") && !options.skip_check {
+        title!("🪃", "Testing");
+        if let Some(check) = lulu.script.check {
+            bash_command(check, "Test", "check")?;
         }
+        record_phase(&srcdir, "check")?;
     }
 
     // Package
-    title!("🔩", "Packaging");
-    env::set_current_dir(srcdir.display().to_string()).unwrap();
-    if !Command::new("fakeroot")
-        .env("basedir", basedir.display().to_string())
-        .env("srcdir", srcdir.display().to_string())
-        .env("pkgdir", pkgdir.display().to_string())
-        .arg("--")
-        .arg("bash")
-        .arg("-ec")
-        .arg(lulu.script.package)
-        .spawn()
-        .expect("Failed to execute command")
-        .wait()
-        .unwrap()
-        .success()
-    {
-        error!("Packaging failed");
-        std::process::exit(1);
+    for (output, pkgdir) in outputs {
+        // Files of a previous packaging must not end up in the package
+        std::fs::remove_dir_all(pkgdir)
+            .and_then(|_| std::fs::create_dir_all(pkgdir))
+            .map_err(|e| LuluError::Build(format!("Failed to clean pkgdir: {}", e)))?;
+
+        let (phase, log_name) = if output.name == lulu.package.name {
+            title!("🔩", "Packaging");
+            ("Packaging".to_string(), "package".to_string())
+        } else {
+            title!("🔩", "Packaging {}", Paint::cyan(&output.name).italic());
+            (
+                format!("Packaging {}", output.name),
+                format!("package-{}", output.name),
+            )
+        };
+        set_current_dir(&srcdir)?;
+        let mut fakeroot = command("fakeroot");
+        fakeroot
+            .arg("--")
+            .arg("bash")
+            .arg("-ec")
+            .arg(env.script(&output.package));
+        run(fakeroot, &phase, &log_name, pkgdir)?;
     }
+    record_phase(&srcdir, "package")?;
 
-    env::set_current_dir(basedir.display().to_string()).unwrap();
+    set_current_dir(&basedir)
 }
 
-pub fn install(name: Option<String>, no_install: bool) {
-    let db = match open_db() {
-        Ok(db) => db,
-        Err(e) => {
-            panic!("{:?}", e);
-        }
-    };
+/// Phases of a build, in order
+const PHASES: [&str; 4] = ["prepare", "build", "check", "package"];
+
+/// Directory holding the state of a build (completed phases, version printed by pkgver)
+///
+/// It's the git directory of the source so that it never shows in `git status` and goes away with
+/// the source directory.
+fn state_dir(srcdir: &Path) -> PathBuf {
+    srcdir.join(".git")
+}
+
+/// File recording the phases which completed
+fn phases_path(srcdir: &Path) -> PathBuf {
+    state_dir(srcdir).join("lulu-phases")
+}
+
+fn completed_phases(srcdir: &Path) -> Vec<String> {
+    match read_to_string(phases_path(srcdir)) {
+        Ok(contents) => contents.lines().map(str::to_string).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Record that `phase` completed, phases after it will have to run again
+fn record_phase(srcdir: &Path, phase: &str) -> Result<(), LuluError> {
+    let index = |phase: &str| PHASES.iter().position(|p| *p == phase);
+    let mut phases: Vec<String> = completed_phases(srcdir)
+        .into_iter()
+        .filter(|p| index(p) < index(phase))
+        .collect();
+    phases.push(phase.to_string());
+    std::fs::write(phases_path(srcdir), phases.join("\n"))
+        .map_err(|e| LuluError::Build(format!("Failed to record phase {}: {}", phase, e)))
+}
+
+fn reset_phases(srcdir: &Path) -> Result<(), LuluError> {
+    match std::fs::remove_file(phases_path(srcdir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(LuluError::Build(format!(
+            "Failed to reset build phases: {}",
+            e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Make sure bubblewrap can run isolated builds
+fn check_isolation() -> Result<(), LuluError> {
+    check_bwrap().map_err(|e| {
+        tip!(
+            "Install bubblewrap {}.{} or later, or build without --isolated",
+            BWRAP_MIN_VERSION.0,
+            BWRAP_MIN_VERSION.1
+        );
+        LuluError::Build(e)
+    })
+}
+
+pub fn install(
+    names: Vec<String>,
+    no_install: bool,
+    isolated: bool,
+    split: Vec<String>,
+) -> Result<(), LuluError> {
+    run_as_root("install")?;
+
+    if names.len() > 1 && !split.is_empty() {
+        return Err(LuluError::Other(
+            "--split can only be used when installing a single package".to_string(),
+        ));
+    }
+
+    let db = open_db()?;
+
+    let build_config = build_config()?;
+    let isolated = isolated || build_config.isolated;
+    if isolated {
+        check_isolation()?;
+    }
 
     let mut ctx = Context {
         no_install,
-        db: db.clone(),
+        standalone: false,
+        output: None,
+        options: BuildOptions::default(),
+        isolated,
+        env: build_config.env,
+        db: Some(db),
+    };
+
+    // Every recipe is fetched before anything is built
+    let names: Vec<Option<String>> = if names.is_empty() {
+        vec![None]
+    } else {
+        names.into_iter().map(Some).collect()
+    };
+    let mut requests = Vec::<Request>::new();
+    for name in names {
+        let (name, reference) = parse_name(name);
+        let (path, lulu, repository) = fetch_recipe(name, &ctx)?;
+        requests.push(Request {
+            path,
+            lulu,
+            reference,
+            split: split.clone(),
+            repository,
+        });
+    }
+    install_recipes(requests, &mut ctx)
+}
+
+/// Build a package without installing it nor recording anything in the db
+///
+/// Root is only needed when build dependencies have to be installed.
+pub fn build(
+    name: Option<String>,
+    output: PathBuf,
+    isolated: bool,
+    options: BuildOptions,
+) -> Result<(), LuluError> {
+    let db = if Path::new(DB_PATH).is_dir() {
+        Some(open_db()?)
+    } else {
+        None
     };
+
+    let (name, reference) = parse_name(name);
+
+    let build_config = build_config()?;
+    let isolated = isolated || build_config.isolated;
+    if isolated {
+        check_isolation()?;
+    }
+
+    // The output directory belongs to the user since packages are written by the build process
+    fork_wait(|| {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        builder.create(&output).map_err(|e| {
+            LuluError::Other(format!("Failed to create {}: {}", output.display(), e))
+        })?;
+        Ok(())
+    })?;
+    let output = output
+        .canonicalize()
+        .map_err(|e| LuluError::Other(format!("Failed to resolve {}: {}", output.display(), e)))?;
+
+    let mut ctx = Context {
+        no_install: true,
+        standalone: true,
+        output: Some(output),
+        options,
+        isolated,
+        env: build_config.env,
+        db,
+    };
+    let (path, lulu, repository) = fetch_recipe(name, &ctx)?;
+    let request = Request {
+        path,
+        lulu,
+        reference,
+        split: vec![],
+        repository,
+    };
+    install_recipes(vec![request], &mut ctx)
+}
+
+/// `[build]` section of the configuration, defaults if there is no configuration
+fn build_config() -> Result<BuildConfig, LuluError> {
+    if !Path::new(CONFIG_PATH).is_file() {
+        return Ok(BuildConfig::default());
+    }
+    let config = read_config()?.build;
+    script_env::validate(&config.env)
+        .map_err(|e| LuluError::Config(format!("Invalid [build.env] in {}: {}", CONFIG_PATH, e)))?;
+    Ok(config)
+}
+
+/// Name and revision of a package given on the command line
+///
+/// There is no name when only a revision is given (`@ref`), the current directory is used.
+fn parse_name(name: Option<String>) -> (Option<String>, Option<String>) {
     match name {
-        Some(n) => {
-            if n.contains("://") || n.starts_with("git@") {
-                install_git(n, &mut ctx)
-            } else {
-                install_db(n, &mut ctx)
-            }
+        Some(n) => match split_reference(&n) {
+            (n, reference) if n.is_empty() => (None, reference),
+            (n, reference) => (Some(n), reference),
+        },
+        None => (None, None),
+    }
+}
+
+/// Split `name@ref` into the name and the revision to build
+///
+/// Care is taken to not split urls like `git@host:repo.git` or `https://user@host/repo.git`.
+fn split_reference(name: &str) -> (String, Option<String>) {
+    match name.rsplit_once('@') {
+        Some((left, reference))
+            if !reference.is_empty()
+                && !reference.contains(':')
+                && left != "git"
+                && left
+                    .split_once("://")
+                    .map_or(true, |(_, rest)| rest.contains('/')) =>
+        {
+            (left.to_string(), Some(reference.to_string()))
         }
-        None => install_local(&mut ctx),
+        _ => (name.to_string(), None),
     }
 }
 
+/// Which phases of a build to run
+#[derive(Clone, Default)]
+pub struct BuildOptions {
+    pub skip_check: bool,
+    pub no_prepare: bool,
+    /// Keep the source directory of a previous build and resume it
+    pub keep_srcdir: bool,
+    /// Phase to start from, previous phases are expected to be done
+    pub from_phase: Option<String>,
+}
+
 #[derive(Clone)]
 struct Context {
     pub no_install: bool,
-    pub db: Db,
+    /// Only build: don't install missing dependencies nor touch the db
+    pub standalone: bool,
+    /// Where to write built packages, the package directory if none
+    pub output: Option<PathBuf>,
+    pub options: BuildOptions,
+    /// Build in an isolated build root
+    pub isolated: bool,
+    /// Variables of `[build.env]` in the configuration
+    pub env: BTreeMap<String, String>,
+    pub db: Option<Db>,
+}
+
+impl Context {
+    /// The db, which installs need
+    fn db(&self) -> Result<&Db, LuluError> {
+        self.db.as_ref().ok_or(LuluError::Db(format!(
+            "No database in {}, run `lulu setup` first",
+            DB_PATH
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deb_path, fetch_recipe, parse_name, split_reference, BuildOptions, Context};
+    use crate::utils::testing::write_recipe;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    fn context() -> Context {
+        Context {
+            no_install: true,
+            standalone: true,
+            output: None,
+            options: BuildOptions::default(),
+            isolated: false,
+            env: BTreeMap::new(),
+            db: None,
+        }
+    }
+
+    #[test]
+    fn split_reference_of_names() {
+        assert_eq!(split_reference("lulu"), ("lulu".to_string(), None));
+        assert_eq!(
+            split_reference("lulu@v1.0"),
+            ("lulu".to_string(), Some("v1.0".to_string()))
+        );
+        assert_eq!(split_reference("lulu@"), ("lulu@".to_string(), None));
+        assert_eq!(
+            split_reference("main/lulu@dev"),
+            ("main/lulu".to_string(), Some("dev".to_string()))
+        );
+    }
+
+    #[test]
+    fn split_reference_of_urls() {
+        assert_eq!(
+            split_reference("git@host:repo.git"),
+            ("git@host:repo.git".to_string(), None)
+        );
+        assert_eq!(
+            split_reference("git@host:repo.git@v1"),
+            ("git@host:repo.git".to_string(), Some("v1".to_string()))
+        );
+        assert_eq!(
+            split_reference("https://user@host/repo.git"),
+            ("https://user@host/repo.git".to_string(), None)
+        );
+        assert_eq!(
+            split_reference("https://host/repo.git@main"),
+            (
+                "https://host/repo.git".to_string(),
+                Some("main".to_string())
+            )
+        );
+        assert_eq!(
+            split_reference("https://user@host/repo.git@1a2b3c4"),
+            (
+                "https://user@host/repo.git".to_string(),
+                Some("1a2b3c4".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn parse_name_of_current_directory() {
+        assert_eq!(parse_name(None), (None, None));
+        assert_eq!(
+            parse_name(Some("@v2".to_string())),
+            (None, Some("v2".to_string()))
+        );
+        assert_eq!(
+            parse_name(Some("lulu".to_string())),
+            (Some("lulu".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn fetch_recipe_of_paths() {
+        // Relative paths are resolved from the current directory
+        let dir = tempfile::tempdir_in(".").unwrap();
+        let relative = Path::new(".")
+            .join(dir.path().file_name().unwrap())
+            .join("pkg");
+        write_recipe(&relative, "foo");
+        let absolute = relative.canonicalize().unwrap();
+
+        for name in [&relative, &absolute] {
+            let (path, lulu, repository) =
+                fetch_recipe(Some(name.display().to_string()), &context()).unwrap();
+            assert_eq!(path, absolute);
+            assert_eq!(lulu.package.name, "foo");
+            assert_eq!(repository, None);
+        }
+    }
+
+    #[test]
+    fn deb_paths_of_several_recipes() {
+        // Like `lulu install a b`: packages are installed together once both recipes are built
+        let dir = tempfile::tempdir_in(".").unwrap();
+        let recipes = ["a", "b"].map(|name| {
+            let path = Path::new(".")
+                .join(dir.path().file_name().unwrap())
+                .join(name);
+            write_recipe(&path, name);
+            fetch_recipe(Some(path.display().to_string()), &context()).unwrap()
+        });
+        let debs: Vec<_> = recipes
+            .iter()
+            .map(|(path, lulu, _)| deb_path(&lulu.package.name, "1:1.0", path, &context()))
+            .collect();
+        for ((path, _, _), deb) in recipes.iter().zip(&debs) {
+            std::fs::write(deb, "").unwrap();
+            assert!(deb.is_absolute());
+            assert_eq!(deb.parent(), Some(path.as_path()));
+        }
+        assert!(debs[0].ends_with("a/a-1.0.deb"));
+        assert!(debs.iter().all(|deb| deb.is_file()));
+
+        let output = dir.path().canonicalize().unwrap();
+        let ctx = Context {
+            output: Some(output.clone()),
+            ..context()
+        };
+        assert_eq!(
+            deb_path("a", "1.0", &recipes[0].0, &ctx),
+            output.join("a-1.0.deb")
+        );
+    }
 }
@@ -1,13 +1,24 @@
 use crate::error;
+use crate::error::LuluError;
 use crate::utils::db::open_db;
 use std::path::Path;
 
 use crate::model::{DbPackage, Installed};
-use crate::package::Lulu;
 use crate::utils::lulu::lulu_file;
 use yansi::{Color, Paint};
 
-fn display(id: String, installed: Option<Installed>, lulu: Option<Lulu>) {
+/// Description of a package, parsing its LULU.toml when the db doesn't have it
+fn description(package: DbPackage) -> Option<String> {
+    if package.description.is_some() {
+        return package.description;
+    }
+    match lulu_file(Path::new(&package.path).join("LULU.toml")) {
+        Ok(Ok(lulu)) => Some(lulu.package.description),
+        _ => None,
+    }
+}
+
+fn display(id: String, installed: Option<Installed>, description: Option<String>) {
     println!(
         "{}\t{}",
         Paint::cyan(id).bold(),
@@ -17,20 +28,14 @@ fn display(id: String, installed: Option<Installed>, lulu: Option<Lulu>) {
             Paint::default("Not installed".to_string()).dimmed()
         }
     );
-    if lulu.is_some() {
-        println!("  {}", lulu.unwrap().package.description);
+    if let Some(description) = description {
+        println!("  {}", description);
     }
     println!();
 }
 
-pub fn list(installed: bool) {
-    let db = match open_db() {
-        Ok(db) => db,
-        Err(e) => {
-            error!("Failed to open database");
-            panic!("{:?}", e);
-        }
-    };
+pub fn list(installed: bool) -> Result<(), LuluError> {
+    let db = open_db()?;
 
     if installed {
         db.clone()
@@ -50,7 +55,7 @@ pub fn list(installed: bool) {
                         return;
                     }
                 };
-                let lulu = match db
+                let description = match db
                     .clone()
                     .collection("packages")
                     .doc(doc.id.as_str())
@@ -58,20 +63,14 @@ pub fn list(installed: bool) {
                 {
                     Ok(opt) => match opt {
                         None => None,
-                        Some(data) => match lulu_file(Path::new(&data.path).join("LULU.toml")) {
-                            Ok(res) => match res {
-                                Ok(lulu) => Some(lulu),
-                                Err(_) => None,
-                            },
-                            Err(_) => None,
-                        },
+                        Some(data) => description(data),
                     },
                     Err(_) => {
                         error!("Failed get document");
                         return;
                     }
                 };
-                display(doc.clone().id, Some(installed), lulu);
+                display(doc.clone().id, Some(installed), description);
             });
     } else {
         db.clone()
@@ -91,23 +90,19 @@ pub fn list(installed: bool) {
                         return;
                     }
                 };
-                let lulu = match doc.doc.clone().get::<DbPackage>() {
+                let description = match doc.doc.clone().get::<DbPackage>() {
                     Ok(opt) => match opt {
                         None => None,
-                        Some(data) => match lulu_file(Path::new(&data.path).join("LULU.toml")) {
-                            Ok(res) => match res {
-                                Ok(lulu) => Some(lulu),
-                                Err(_) => None,
-                            },
-                            Err(_) => None,
-                        },
+                        Some(data) => description(data),
                     },
                     Err(_) => {
                         error!("Failed get document");
                         return;
                     }
                 };
-                display(doc.clone().id, installed, lulu);
+                display(doc.clone().id, installed, description);
             });
     }
+
+    Ok(())
 }
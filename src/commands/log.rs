@@ -0,0 +1,102 @@
+use crate::error::LuluError;
+use crate::utils::build_log::{builds, BuildLog};
+use crate::{error, title};
+use yansi::{Color, Paint};
+
+fn build_name(log: &BuildLog) -> String {
+    log.path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn summary(log: &BuildLog) -> String {
+    let records = log.records();
+    match records.last() {
+        None => "no phase run".to_string(),
+        Some(last) if last.success => format!("ok ({} phases)", records.len()),
+        Some(last) => format!(
+            "{} failed with status {}",
+            last.phase,
+            last.status
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "signal".to_string())
+        ),
+    }
+}
+
+pub fn log(
+    name: String,
+    build: Option<String>,
+    phase: Option<String>,
+    list: bool,
+) -> Result<(), LuluError> {
+    let builds = builds(&name);
+    if builds.is_empty() {
+        return Err(LuluError::PackageNotFound(format!(
+            "No build log for {}",
+            name
+        )));
+    }
+
+    if list {
+        title!("📜", "Builds of {}", Paint::cyan(&name).bold());
+        for log in &builds {
+            println!("{}\t{}", Paint::cyan(build_name(log)).bold(), summary(log));
+        }
+        return Ok(());
+    }
+
+    let log = match build {
+        Some(build) => builds
+            .iter()
+            .find(|log| build_name(log) == build)
+            .ok_or_else(|| {
+                LuluError::PackageNotFound(format!("No build {} for {}", build, name))
+            })?,
+        None => builds.last().expect("There should be at least one build"),
+    };
+
+    title!(
+        "📜",
+        "Build {} of {} in {}",
+        Paint::cyan(build_name(log)).bold(),
+        Paint::cyan(&name).bold(),
+        Paint::cyan(log.path.display()).underline()
+    );
+    let records = log.records();
+    for record in &records {
+        let status = match record.status {
+            Some(code) => code.to_string(),
+            None => "signal".to_string(),
+        };
+        let status = if record.success {
+            Paint::green(status)
+        } else {
+            Paint::red(status)
+        };
+        println!(
+            "{}\tstatus {}\t{:.1}s",
+            Paint::cyan(&record.phase).bold(),
+            status,
+            record.duration
+        );
+    }
+
+    let phases: Vec<String> = match phase {
+        Some(phase) => vec![phase],
+        None => records.iter().map(|record| record.phase.clone()).collect(),
+    };
+    for phase in phases {
+        title!("📄", "{}", phase);
+        match std::fs::read_to_string(log.phase_path(&phase)) {
+            Ok(contents) => print!("{}", contents),
+            Err(e) => {
+                error!("Failed to read log of {}", phase);
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,30 +1,87 @@
+mod autoremove;
+mod cache;
 mod install;
 mod list;
+mod log;
 mod remove;
+mod repo;
 mod setup;
 mod update;
 mod upgrade;
 
-pub fn install(name: Option<String>, no_install: bool) {
-    install::install(name, no_install)
+use std::path::PathBuf;
+
+use crate::error::LuluError;
+
+pub use install::BuildOptions;
+
+pub fn install(
+    names: Vec<String>,
+    no_install: bool,
+    isolated: bool,
+    split: Vec<String>,
+) -> Result<(), LuluError> {
+    install::install(names, no_install, isolated, split)
+}
+
+pub fn build(
+    name: Option<String>,
+    output: PathBuf,
+    isolated: bool,
+    options: BuildOptions,
+) -> Result<(), LuluError> {
+    install::build(name, output, isolated, options)
 }
 
-pub fn setup() {
+pub fn setup() -> Result<(), LuluError> {
     setup::setup()
 }
 
-pub fn update(no_check: bool) {
+pub fn update(no_check: bool) -> Result<(), LuluError> {
     update::update(no_check)
 }
 
-pub fn upgrade() {
+pub fn upgrade() -> Result<(), LuluError> {
     upgrade::upgrade()
 }
 
-pub fn remove(name: String, purge: bool) {
+pub fn remove(name: String, purge: bool) -> Result<(), LuluError> {
     remove::remove(name, purge)
 }
 
-pub fn list(installed: bool) {
+pub fn autoremove() -> Result<(), LuluError> {
+    autoremove::autoremove()
+}
+
+pub fn list(installed: bool) -> Result<(), LuluError> {
     list::list(installed)
 }
+
+pub fn cache(clean: bool) -> Result<(), LuluError> {
+    cache::cache(clean)
+}
+
+pub fn repo_index(path: Option<PathBuf>) -> Result<(), LuluError> {
+    repo::index(path)
+}
+
+pub fn repo_add(name: String, source: String, branch: Option<String>) -> Result<(), LuluError> {
+    repo::add(name, source, branch)
+}
+
+pub fn repo_remove(name: String) -> Result<(), LuluError> {
+    repo::remove(name)
+}
+
+pub fn repo_list() -> Result<(), LuluError> {
+    repo::list()
+}
+
+pub fn log(
+    name: String,
+    build: Option<String>,
+    phase: Option<String>,
+    list: bool,
+) -> Result<(), LuluError> {
+    log::log(name, build, phase, list)
+}
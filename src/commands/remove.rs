@@ -1,42 +1,40 @@
-use crate::utils::db::open_and_lock_db;
-use crate::{error, tip, title, warning};
+use crate::db::Db;
+use crate::error::LuluError;
+use crate::title;
+use crate::utils::db::{open_and_lock_db, unlock_db};
+use crate::utils::privileges::run_as_root;
 use rust_apt::cache::Cache;
 use rust_apt::raw::progress::{AptAcquireProgress, AptInstallProgress};
 use yansi::{Color, Paint};
 
-pub fn remove(name: String, purge: bool) {
-    if sudo::check() != sudo::RunningAs::Root {
-        warning!("Lulu must be run as root");
-        match sudo::with_env(&["USER", "HOME"]) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Failed to run as root");
-                tip!("Run lulu as root with `sudo lulu remove`");
-                panic!("{:?}", e);
-            }
-        }
-    }
-    let mut db = match open_and_lock_db() {
-        Ok(db) => db,
-        Err(e) => {
-            panic!("{:?}", e);
-        }
-    };
+pub fn remove(name: String, purge: bool) -> Result<(), LuluError> {
+    run_as_root("remove")?;
+    let mut db = open_and_lock_db()?;
+
+    let result = remove_locked(&db, name, purge);
 
+    unlock_db(&mut db)?;
+    result
+}
+
+fn remove_locked(db: &Db, name: String, purge: bool) -> Result<(), LuluError> {
     let mut document = db.clone().collection("installed").doc(name.as_str());
 
     if !document.exist {
-        error!("Package {} not installed with lulu", name);
-        return;
+        return Err(LuluError::PackageNotFound(format!(
+            "Package {} not installed with lulu",
+            name
+        )));
     }
 
     title!("📦", "Uninstalling {}", name);
-    let cache = match Cache::new::<bool>(&[]) {
-        Ok(c) => c,
-        Err(_) => todo!(),
-    };
+    let cache = Cache::new::<bool>(&[])
+        .map_err(|e| LuluError::Apt(format!("Failed to open apt cache: {}", e)))?;
 
-    let to_uninstall = cache.get(&name).expect("Should be in the cache");
+    let to_uninstall = cache.get(&name).ok_or(LuluError::PackageNotFound(format!(
+        "Package {} not found in apt cache",
+        name
+    )))?;
     to_uninstall.mark_delete(purge);
     to_uninstall.protect();
 
@@ -44,25 +42,21 @@ pub fn remove(name: String, purge: bool) {
     let mut install_progress = AptInstallProgress::new_box();
 
     match cache.commit(&mut acquire_progress, &mut install_progress) {
-        Ok(_) => match document.delete() {
-            Ok(_) => {}
-            Err(_) => {
-                error!(
-                    "Failed to delete {} from database, db is now in broken state",
-                    name
-                );
-            }
-        },
-        Err(_) => {
-            error!("Failed to uninstall {}", name);
+        Ok(_) => {
+            document.delete().map_err(|e| {
+                LuluError::Db(format!(
+                    "Failed to delete {} from database, db is now in broken state: {}",
+                    name, e
+                ))
+            })?;
         }
-    };
-
-    match db.unlock() {
-        Ok(_) => {}
         Err(e) => {
-            error!("Failed to unlock database");
-            panic!("{:?}", e);
+            return Err(LuluError::Apt(format!(
+                "Failed to uninstall {}: {}",
+                name, e
+            )));
         }
     };
+
+    Ok(())
 }
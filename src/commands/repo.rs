@@ -0,0 +1,266 @@
+use crate::commands::update::record_packages;
+use crate::db::{Condition, Db};
+use crate::error::LuluError;
+use crate::model::Repository;
+use crate::utils::config::{read_config, read_config_document, write_config_document, CONFIG_PATH};
+use crate::utils::db::{open_and_lock_db, unlock_db};
+use crate::utils::index::{self, INDEX_FILE};
+use crate::utils::privileges::run_as_root;
+use crate::{error, success, tip, title, warning};
+use std::env;
+use std::path::{Path, PathBuf};
+use toml_edit::{value, ArrayOfTables, Document, InlineTable, Item, Table, Value};
+use yansi::{Color, Paint};
+
+/// Write the index of a recipe repository
+pub fn index(path: Option<PathBuf>) -> Result<(), LuluError> {
+    let root = match path {
+        Some(path) => path,
+        None => env::current_dir()
+            .map_err(|e| LuluError::Other(format!("Failed to get current directory: {}", e)))?,
+    };
+    title!("📜", "Indexing {}", Paint::cyan(root.display()).underline());
+
+    let entries = index::scan(&root)
+        .map_err(|e| LuluError::Other(format!("Failed to read {}: {}", root.display(), e)))?;
+    let mut packages = vec![];
+    let mut invalid = 0;
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                println!("{}\t{}", Paint::cyan(&entry.name).italic(), entry.path);
+                packages.push(entry);
+            }
+            Err(e) => {
+                error!("{}", e);
+                invalid += 1;
+            }
+        }
+    }
+    for (name, paths) in index::duplicates(&packages) {
+        error!("Package {} has several recipes: {}", name, paths.join(", "));
+        invalid += 1;
+    }
+    if invalid > 0 {
+        return Err(LuluError::Manifest(format!(
+            "Found {} errors, {} was not written",
+            invalid, INDEX_FILE
+        )));
+    }
+
+    let count = packages.len();
+    let path = index::write(&root, packages).map_err(LuluError::Other)?;
+    success!("Wrote {} ({} packages)", path.display(), count);
+    Ok(())
+}
+
+/// Add a repository to the config
+pub fn add(name: String, source: String, branch: Option<String>) -> Result<(), LuluError> {
+    run_as_root("repo add")?;
+    let mut db = open_and_lock_db()?;
+
+    let result = add_locked(name, source, branch);
+
+    unlock_db(&mut db)?;
+    result
+}
+
+fn add_locked(name: String, source: String, branch: Option<String>) -> Result<(), LuluError> {
+    check_name(&name)?;
+    let config = read_config()?;
+    if config.repositories.iter().any(|r| r.name == name) {
+        return Err(LuluError::Config(format!(
+            "Repository {} already exists in {}",
+            name, CONFIG_PATH
+        )));
+    }
+
+    // Local directories are only recognized by absolute paths
+    let is_url = source.contains("://") || source.starts_with("git@");
+    let source = match Path::new(&source).canonicalize() {
+        Ok(path) if !is_url && path.is_dir() => path.display().to_string(),
+        _ => source,
+    };
+    let repository = Repository {
+        name: name.clone(),
+        source: source.clone(),
+        branch: branch.clone(),
+        priority: 0,
+        include: vec![],
+        exclude: vec![],
+    };
+    if let Some(path) = repository.local_path() {
+        if !path.is_dir() {
+            warning!("{} is not a directory yet", path.display());
+        }
+    }
+
+    title!("➕", "Adding repository {}", name);
+    let mut document = read_config_document()?;
+    match document
+        .entry("repositories")
+        .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+    {
+        Item::ArrayOfTables(tables) => {
+            let mut table = Table::new();
+            table["name"] = value(&name);
+            table["source"] = value(&source);
+            if let Some(branch) = &branch {
+                table["branch"] = value(branch);
+            }
+            tables.push(table);
+        }
+        Item::Value(Value::Array(array)) => {
+            let mut table = InlineTable::new();
+            table.insert("name", name.as_str().into());
+            table.insert("source", source.as_str().into());
+            if let Some(branch) = &branch {
+                table.insert("branch", branch.as_str().into());
+            }
+            array.push(table);
+        }
+        _ => {
+            return Err(LuluError::Config(format!(
+                "repositories of {} is not an array",
+                CONFIG_PATH
+            )))
+        }
+    }
+    write_config_document(&document)?;
+
+    success!("Added {}", name);
+    tip!("Run `lulu update` to get its recipes");
+    Ok(())
+}
+
+/// Remove a repository from the config and forget its recipes
+pub fn remove(name: String) -> Result<(), LuluError> {
+    run_as_root("repo remove")?;
+    let mut db = open_and_lock_db()?;
+
+    let result = remove_locked(&db, name);
+
+    unlock_db(&mut db)?;
+    result
+}
+
+fn remove_locked(db: &Db, name: String) -> Result<(), LuluError> {
+    let mut document = read_config_document()?;
+    if !remove_repository(&mut document, &name) {
+        return Err(LuluError::Config(format!(
+            "Repository {} not found in {}",
+            name, CONFIG_PATH
+        )));
+    }
+
+    title!("➖", "Removing repository {}", name);
+    write_config_document(&document)?;
+
+    // Recipes of repositories which are not in the config anymore are dropped
+    let config = read_config()?;
+    record_packages(db, &config, vec![])?;
+
+    // A hand written name could point outside of the repositories directory
+    if check_name(&name).is_ok() {
+        let clone = Path::new("/var/lib/lulu/repositories").join(&name);
+        if clone.exists() {
+            std::fs::remove_dir_all(&clone).map_err(|e| {
+                LuluError::Other(format!("Failed to remove {}: {}", clone.display(), e))
+            })?;
+        }
+    }
+
+    let installed = db
+        .clone()
+        .collection("installed")
+        .wherr(
+            "repository".to_string(),
+            Condition::Equal,
+            serde_json::Value::from(name.clone()),
+        )
+        .map(|found| found.get())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|doc| doc.id)
+        .collect::<Vec<String>>();
+    if !installed.is_empty() {
+        warning!(
+            "{} came from {}, they won't be upgraded",
+            installed.join(", "),
+            name
+        );
+    }
+
+    success!("Removed {}", name);
+    Ok(())
+}
+
+/// Remove a repository from the config document, return whether it was there
+fn remove_repository(document: &mut Document, name: &str) -> bool {
+    match document.get_mut("repositories") {
+        Some(Item::ArrayOfTables(tables)) => {
+            let position = tables
+                .iter()
+                .position(|table| table.get("name").and_then(|n| n.as_str()) == Some(name));
+            position.map(|i| tables.remove(i)).is_some()
+        }
+        Some(Item::Value(Value::Array(array))) => {
+            let position = array.iter().position(|table| {
+                table
+                    .as_inline_table()
+                    .and_then(|table| table.get("name"))
+                    .and_then(|n| n.as_str())
+                    == Some(name)
+            });
+            position.map(|i| array.remove(i)).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// Show the repositories of the config
+pub fn list() -> Result<(), LuluError> {
+    let config = read_config()?;
+
+    for repo in config.repositories {
+        println!("{}\t{}", Paint::cyan(&repo.name).bold(), repo.source);
+        let mut details = vec![];
+        if repo.local_path().is_some() {
+            details.push("local directory".to_string());
+        }
+        if let Some(branch) = &repo.branch {
+            details.push(format!("branch {}", branch));
+        }
+        if repo.priority != 0 {
+            details.push(format!("priority {}", repo.priority));
+        }
+        if !repo.include.is_empty() {
+            details.push(format!("include {}", repo.include.join(", ")));
+        }
+        if !repo.exclude.is_empty() {
+            details.push(format!("exclude {}", repo.exclude.join(", ")));
+        }
+        if !details.is_empty() {
+            println!("  {}", Paint::default(details.join(", ")).dimmed());
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Check that a repository name can be used in `repository/name` and as a directory name
+fn check_name(name: &str) -> Result<(), LuluError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(LuluError::Other(format!(
+            "Invalid repository name {:?}, use letters, digits, '-', '_' and '.'",
+            name
+        )));
+    }
+    Ok(())
+}
@@ -1,199 +1,312 @@
-use crate::db::Condition;
-use crate::model::{Config, DbPackage};
-use crate::utils::db::open_and_lock_db;
-use crate::utils::lulu::lulu_file;
-use crate::{error, success, tip, title, warning};
-use serde_json::Value;
-use std::io::Read;
+use crate::db::Db;
+use crate::error::LuluError;
+use crate::model::{Config, DbPackage, Recipe, Repository};
+use crate::utils::config::read_config;
+use crate::utils::db::{open_and_lock_db, unlock_db};
+use crate::utils::git::reset_to_branch;
+use crate::utils::glob;
+use crate::utils::index::{self, IndexEntry, INDEX_FILE};
+use crate::utils::privileges::run_as_root;
+use crate::{error, success, title, warning};
+use log::trace;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 use yansi::{Color, Paint};
 
-pub fn update(_no_check: bool) {
-    if sudo::check() != sudo::RunningAs::Root {
-        warning!("Lulu must be run as root");
-        match sudo::with_env(&["USER", "HOME"]) {
-            Ok(_) => {}
+pub fn update(_no_check: bool) -> Result<(), LuluError> {
+    run_as_root("update")?;
+    let mut db = open_and_lock_db()?;
+
+    let result = update_locked(&db);
+
+    unlock_db(&mut db)?;
+    result
+}
+
+fn update_locked(db: &Db) -> Result<(), LuluError> {
+    title!("📁", "Getting repositories from config");
+    let config = read_config()?;
+
+    let mut failed = Vec::<String>::new();
+    let mut scanned = Vec::<(String, Vec<(String, Recipe)>)>::new();
+    for repo in config.repositories.iter() {
+        title!("🔎", "Updating {}", repo.name);
+        let result = match repo.local_path() {
+            Some(path) => check_local_repository(repo, &path),
+            None => update_repository(repo),
+        }
+        .and_then(|_| {
+            // Recipes are always read again since the include and exclude globs may have changed
+            scanned.push((repo.name.clone(), scan_repository(repo)?));
+            Ok(())
+        });
+        match result {
+            Ok(_) => success!("Up to date"),
             Err(e) => {
-                error!("Failed to run as root");
-                tip!("Run lulu as root with `sudo lulu update`");
-                panic!("{:?}", e);
+                error!("{}", e);
+                failed.push(repo.name.clone());
             }
         }
     }
-    let mut db = match open_and_lock_db() {
-        Ok(db) => db,
+    record_packages(db, &config, scanned)?;
+
+    if !failed.is_empty() {
+        return Err(LuluError::Git(format!(
+            "Failed to update {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Check a repository read in place
+fn check_local_repository(repo: &Repository, path: &Path) -> Result<(), LuluError> {
+    if !path.is_dir() {
+        return Err(LuluError::Other(format!(
+            "{} is not a directory",
+            path.display()
+        )));
+    }
+    if repo.branch.is_some() {
+        warning!("{} is a local directory, its branch is ignored", repo.name);
+    }
+    Ok(())
+}
+
+/// Clone or update a repository
+fn update_repository(repo: &Repository) -> Result<(), LuluError> {
+    let path = repo.root();
+    let mut remote = git2::Remote::create_detached(repo.source.clone())
+        .map_err(|e| LuluError::Git(format!("Failed to create update: {}", e)))?;
+    remote
+        .connect(git2::Direction::Fetch)
+        .map_err(|e| LuluError::Git(format!("Failed to connect to repository: {}", e)))?;
+    let branch = match &repo.branch {
+        Some(branch) => branch.clone(),
+        None => default_branch(&remote)?,
+    };
+    trace!("Following branch {}", branch);
+    // Only branches are followed, a tag of the same name must not shadow it
+    let head = format!("refs/heads/{}", branch);
+    let remote_oid = match remote.list() {
+        Ok(list) => match list.iter().find(|h| h.name() == head) {
+            None => {
+                return Err(LuluError::Git(format!(
+                    "Branch {} not found in remote repository",
+                    branch
+                )))
+            }
+            Some(h) => h.oid(),
+        },
         Err(e) => {
-            panic!("{:?}", e);
+            return Err(LuluError::Git(format!(
+                "Failed to get list from remote repository: {}",
+                e
+            )))
         }
     };
 
-    title!("📁", "Getting repositories from config");
-    let file = match std::fs::File::open(Path::new("/etc/lulu.conf")) {
-        Ok(f) => f,
-        Err(e) => {
-            error!("Error while opening /etc/lulu.conf");
-            panic!("{:?}", e);
+    let git_repo = match git2::Repository::open(path.clone()) {
+        Ok(r) => r,
+        Err(_) => {
+            std::fs::create_dir_all(path.as_path())
+                .map_err(|e| LuluError::Git(format!("Can't create repository: {}", e)))?;
+            git2::build::RepoBuilder::new()
+                .branch(&branch)
+                .clone(repo.source.as_str(), path.as_path())
+                .map_err(|e| LuluError::Git(format!("Can't clone repository: {}", e)))?
         }
     };
-    let mut buf_reader = std::io::BufReader::new(file);
-    let mut contents = String::new();
-    match buf_reader.read_to_string(&mut contents) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Failed to read /etc/lulu.conf");
-            panic!("{:?}", e);
-        }
+
+    // The followed branch may have changed since the clone
+    let up_to_date = git_repo.head().map_or(false, |h| {
+        h.name() == Some(head.as_str()) && h.target() == Some(remote_oid)
+    });
+    if !up_to_date {
+        reset_to_branch(&git_repo, "origin", &branch)
+            .map_err(|e| LuluError::Git(format!("Failed to update repository: {}", e)))?;
     }
-    let config: Config = toml::from_str(&contents).unwrap();
-    config.repositories.iter().for_each(|repo| {
-        title!("🔎", "Updating {}", repo.name);
-        let path = Path::new("/var/lib/lulu/repositories").join(repo.name.clone());
-        let mut remote = match git2::Remote::create_detached(repo.source.clone()) {
-            Ok(r) => r,
-            Err(_) => {
-                error!("Failed to create update");
-                return;
-            }
-        };
-        match remote.connect(git2::Direction::Fetch) {
-            Ok(_) => {}
-            Err(_) => {
-                error!("Failed to connect to repository");
-                return;
-            }
-        }
-        let remote_oid = match remote.list() {
-            Ok(list) => match list.first() {
-                None => {
-                    error!("Remote repository seems empty");
-                    return;
-                }
-                Some(head) => head.oid(),
-            },
-            Err(_) => {
-                error!("Failed to get list from remote repository");
-                return;
-            }
-        };
 
-        let mut need_update = false;
+    Ok(())
+}
 
-        let git_repo = match git2::Repository::open(path.clone()) {
-            Ok(r) => r,
-            Err(_) => match std::fs::create_dir_all(path.as_path()) {
-                Ok(_) => match git2::Repository::clone(repo.source.as_str(), path.clone()) {
-                    Ok(r) => {
-                        need_update = true;
-                        r
+/// Find the recipes of a repository, as (name, recipe)
+///
+/// The index of the repository is used when it has one, otherwise every LULU.toml is read.
+fn scan_repository(repo: &Repository) -> Result<Vec<(String, Recipe)>, LuluError> {
+    let root = repo.root();
+    let entries = match index::read(&root) {
+        Ok(Some(index)) => {
+            trace!("Reading {} of {}", INDEX_FILE, repo.name);
+            index
+                .packages
+                .into_iter()
+                .filter(|entry| {
+                    if let Err(e) = index::check_path(&entry.path) {
+                        warning!("{}, {} is ignored", e, entry.name);
+                        return false;
                     }
-                    Err(_) => {
-                        error!("Can't clone repository");
-                        return;
+                    let exists = root.join(&entry.path).join("LULU.toml").is_file();
+                    if !exists {
+                        warning!("{} has no LULU.toml, {} is ignored", entry.path, entry.name);
                     }
-                },
-                Err(_) => {
-                    error!("Can't create repository");
-                    return;
-                }
-            },
-        };
+                    exists
+                })
+                .collect()
+        }
+        result => {
+            if let Err(e) = result {
+                warning!("{}, reading every recipe instead", e);
+            }
+            index::scan(&root)
+                .map_err(|e| LuluError::Other(format!("Failed to read {}: {}", root.display(), e)))?
+                .into_iter()
+                .filter_map(|entry| entry.map_err(|e| warning!("{}", e)).ok())
+                .collect::<Vec<IndexEntry>>()
+        }
+    };
+    let mut entries: Vec<IndexEntry> = entries
+        .into_iter()
+        .filter(|entry| is_included(repo, &entry.path))
+        .collect();
 
-        let local_oid = match git_repo.head() {
-            Ok(head) => match head.target() {
-                None => {
-                    error!("The commit should point to a ref");
-                    return;
-                }
-                Some(oid) => oid,
-            },
-            Err(_) => {
-                error!("There should be at least one commit");
-                return;
+    // Within a repository the first path wins, so that the result doesn't depend on the index
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    for (name, paths) in index::duplicates(&entries) {
+        warning!(
+            "Package {} has several recipes in {}: {}, the first one is used",
+            name,
+            repo.name,
+            paths.join(", ")
+        );
+    }
+    let mut seen = BTreeSet::<String>::new();
+    entries.retain(|entry| seen.insert(entry.name.clone()));
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            let recipe = Recipe {
+                repository: repo.name.clone(),
+                path: root.join(&entry.path).display().to_string(),
+                description: Some(entry.description),
+            };
+            (entry.name, recipe)
+        })
+        .collect())
+}
+
+/// Whether a recipe directory matches the include and exclude globs of a repository
+fn is_included(repo: &Repository, path: &str) -> bool {
+    (repo.include.is_empty() || repo.include.iter().any(|glob| glob::matches(glob, path)))
+        && !repo.exclude.iter().any(|glob| glob::matches(glob, path))
+}
+
+/// Record the recipes of updated repositories in the db
+///
+/// Each package points to the recipe of the repository with the highest priority, or the first
+/// one in the config when priorities are equal.
+pub fn record_packages(
+    db: &Db,
+    config: &Config,
+    scanned: Vec<(String, Vec<(String, Recipe)>)>,
+) -> Result<(), LuluError> {
+    let rank = |repository: &str| {
+        config
+            .repositories
+            .iter()
+            .position(|r| r.name == repository)
+            .map(|i| (Reverse(config.repositories[i].priority), i))
+    };
+
+    let mut before = BTreeMap::<String, DbPackage>::new();
+    for doc in db.clone().collection("packages").get() {
+        match doc.doc.clone().get::<DbPackage>() {
+            Ok(Some(package)) => {
+                before.insert(doc.id, package);
             }
-        };
+            _ => warning!("Failed to read package {}", doc.id),
+        }
+    }
 
-        if remote_oid != local_oid {
-            match crate::utils::git::pull(git_repo, "origin", "master") {
-                // TODO get remote from config
-                Ok(_) => {
-                    need_update = true;
-                }
-                Err(_) => {
-                    error!("Failed to update repository");
-                    return;
-                }
+    let mut packages: BTreeMap<String, Vec<Recipe>> = before
+        .iter()
+        .map(|(name, package)| {
+            let mut recipes = package.recipes.clone();
+            if recipes.is_empty() {
+                recipes.extend(package.recipe(None));
             }
+            (name.clone(), recipes)
+        })
+        .collect();
+    let mut collisions = BTreeSet::<String>::new();
+    for (repository, recipes) in scanned {
+        for list in packages.values_mut() {
+            list.retain(|recipe| recipe.repository != repository);
+        }
+        for (name, recipe) in recipes {
+            let list = packages.entry(name.clone()).or_default();
+            if !list.is_empty() {
+                collisions.insert(name);
+            }
+            list.push(recipe);
+        }
+    }
+
+    for (name, mut recipes) in packages {
+        // Repositories removed from the config are forgotten
+        recipes.retain(|recipe| rank(&recipe.repository).is_some());
+        recipes.sort_by_key(|recipe| rank(&recipe.repository));
+
+        if collisions.contains(&name) && recipes.len() > 1 {
+            warning!(
+                "Package {} is in repositories {}, the one of {} is used",
+                name,
+                recipes
+                    .iter()
+                    .map(|recipe| recipe.repository.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+                recipes[0].repository
+            );
         }
 
-        if need_update {
-            match db.clone().collection("packages").wherr(
-                "repository".to_string(),
-                Condition::Equal,
-                Value::from(repo.name.clone()),
-            ) {
-                Ok(w) => w,
-                Err(_) => {
-                    error!("Failed to update repository");
-                    return;
+        let mut document = db.clone().collection("packages").doc(name.as_str());
+        let result = match recipes.first() {
+            None if document.exist => document.delete(),
+            None => continue,
+            Some(first) => {
+                let package = DbPackage {
+                    repository: first.repository.clone(),
+                    path: first.path.clone(),
+                    description: first.description.clone(),
+                    recipes: recipes.clone(),
+                };
+                if before.get(&name) == Some(&package) {
+                    continue;
                 }
+                document.set(package)
             }
-            .get()
-            .iter()
-            .for_each(|doc| {
-                let _ = doc.doc.clone().delete();
-            });
-            let rd = match std::fs::read_dir(path) {
-                Ok(rd) => rd,
-                Err(_) => {
-                    error!("Failed to update repository");
-                    return;
-                }
-            };
-            rd.for_each(|dir| {
-                if dir.is_ok() {
-                    let dir = dir.unwrap();
-                    if dir.path().is_dir() && dir.path().join("LULU.toml").is_file() {
-                        let lulu = match lulu_file(dir.path().join("LULU.toml")) {
-                            Ok(f) => {
-                                if f.is_ok() {
-                                    f.unwrap()
-                                } else {
-                                    error!("LULU.toml is not deserializable");
-                                    return;
-                                }
-                            }
-                            Err(_) => {
-                                error!("LULU.toml is not readable");
-                                return;
-                            }
-                        };
-                        match db
-                            .clone()
-                            .collection("packages")
-                            .doc(lulu.package.name.as_str())
-                            .set(DbPackage {
-                                repository: repo.name.clone(),
-                                path: dir.path().display().to_string(),
-                            }) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                warning!("Failed to add package {}", lulu.package.name);
-                                eprintln!("{:?}", e);
-                            }
-                        };
-                    }
-                }
-            })
+        };
+        if let Err(e) = result {
+            warning!("Failed to update package {}", name);
+            eprintln!("{:?}", e);
         }
+    }
 
-        success!("Up to date");
-    });
+    Ok(())
+}
 
-    match db.unlock() {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Failed to unlock database");
-            panic!("{:?}", e);
-        }
-    };
+/// Short name of the branch pointed by the HEAD of a connected remote, like `main`
+fn default_branch(remote: &git2::Remote) -> Result<String, LuluError> {
+    let head = remote
+        .default_branch()
+        .map_err(|e| LuluError::Git(format!("Failed to find default branch: {}", e)))?;
+    match head.as_str() {
+        Some(head) => Ok(head.strip_prefix("refs/heads/").unwrap_or(head).to_string()),
+        None => Err(LuluError::Git(
+            "Default branch is not valid utf-8".to_string(),
+        )),
+    }
 }
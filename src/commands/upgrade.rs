@@ -1,112 +1,138 @@
 use crate::commands::install;
-use crate::model::{Config, Installed};
+use crate::db::Db;
+use crate::error::LuluError;
+use crate::model::{Config, DbPackage, Installed};
+use crate::utils::config::read_config;
 use crate::utils::db::open_and_lock_db;
-use crate::{error, tip, title, warning};
-use std::io::Read;
+use crate::utils::git::{find_remote_oid, is_commit_hash};
+use crate::utils::lulu::lulu_file;
+use crate::utils::privileges::run_as_root;
+use crate::{error, title, warning};
+use std::collections::BTreeMap;
 use std::path::Path;
 use yansi::{Color, Paint};
 
-pub fn upgrade() {
-    if sudo::check() != sudo::RunningAs::Root {
-        warning!("Lulu must be run as root");
-        match sudo::with_env(&["USER", "HOME"]) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Failed to run as root");
-                tip!("Run lulu as root with `sudo lulu update`");
-                panic!("{:?}", e);
-            }
-        }
-    }
-    let mut db = match open_and_lock_db() {
-        Ok(db) => db,
-        Err(e) => {
-            panic!("{:?}", e);
-        }
-    };
+pub fn upgrade() -> Result<(), LuluError> {
+    run_as_root("upgrade")?;
+    let mut db = open_and_lock_db()?;
 
-    let file = match std::fs::File::open(Path::new("/etc/lulu.conf")) {
-        Ok(f) => f,
-        Err(e) => {
-            error!("Error while opening /etc/lulu.conf");
-            panic!("{:?}", e);
-        }
-    };
-    let mut buf_reader = std::io::BufReader::new(file);
-    let mut contents = String::new();
-    match buf_reader.read_to_string(&mut contents) {
+    let result = read_config().and_then(|config| upgrade_locked(&db, &config));
+
+    match db.unlock() {
         Ok(_) => {}
-        Err(e) => {
-            error!("Failed to read /etc/lulu.conf");
-            panic!("{:?}", e);
+        Err(_) => {
+            warning!("Database already unlocked");
         }
-    }
-    let config: Config = toml::from_str(&contents).unwrap();
+    };
+    result
+}
 
+fn upgrade_locked(db: &Db, config: &Config) -> Result<(), LuluError> {
     title!("🧨", "Checking for upgrades");
-    db.clone().collection("installed").get().iter().for_each(|p| {
-        if !db.clone().collection("packages").doc(p.id.as_str()).exist {
-            warning!("Skipping {} as it is not in a repository so we don't know what to do if there are updates available", p.id);
-            return;
-        }
 
-        if config.ignore.contains(&p.id) {
-            warning!("Skipping {} as it is in ignore section", p.id);
-            return;
+    // Split packages are upgraded together with the recipe they were built from
+    let mut recipes = BTreeMap::<String, Vec<(String, Installed)>>::new();
+    for p in db.clone().collection("installed").get() {
+        match p.doc.clone().get::<Installed>() {
+            Ok(Some(package)) => {
+                let recipe = package.recipe.clone().unwrap_or(p.id.clone());
+                recipes.entry(recipe).or_default().push((p.id, package));
+            }
+            _ => error!("Failed to check update for {}", p.id),
         }
+    }
 
-        let package = match p.doc.clone().get::<Installed>() {
-            Ok(pkg) => match pkg {
-                None => {
-                    error!("Failed to check update for {}", p.id);
-                    return;
-                }
-                Some(p) => p
-            }
-            Err(_) => {
-                error!("Failed to check update for {}", p.id);
-                return;
+    for (recipe, packages) in recipes {
+        let package = &packages.first().expect("Recipes have packages").1;
+
+        // The recipe of the repository the package was installed from
+        let path = match db
+            .clone()
+            .collection("packages")
+            .doc(recipe.as_str())
+            .get::<DbPackage>()
+        {
+            Ok(Some(data)) => data.recipe(package.repository.as_deref()).map(|r| r.path),
+            _ => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => {
+                warning!("Skipping {} as it is not in a repository so we don't know what to do if there are updates available", recipe);
+                continue;
             }
         };
 
-        let mut remote = match git2::Remote::create_detached(package.source) {
+        if config.ignore.contains(&recipe)
+            || packages.iter().any(|(id, _)| config.ignore.contains(id))
+        {
+            warning!("Skipping {} as it is in ignore section", recipe);
+            continue;
+        }
+
+        // A revision pinned by the user wins over the one of the package
+        let reference = package.pinned.clone().or_else(|| {
+            match lulu_file(Path::new(&path).join("LULU.toml")) {
+                Ok(Ok(lulu)) => lulu.package.pinned_ref(),
+                _ => None,
+            }
+        });
+
+        // A commit never moves, only branches and tags are resolved again
+        if reference.as_deref().map_or(false, is_commit_hash) {
+            continue;
+        }
+
+        let mut remote = match git2::Remote::create_detached(package.source.clone()) {
             Ok(r) => r,
             Err(_) => {
-                error!("Failed to check update for {}", p.id);
-                return;
+                error!("Failed to check update for {}", recipe);
+                continue;
             }
         };
         match remote.connect(git2::Direction::Fetch) {
             Ok(_) => {}
             Err(_) => {
-                error!("Failed to connect to source for {}", p.id);
-                return;
+                error!("Failed to connect to source for {}", recipe);
+                continue;
             }
         }
+
         let remote_oid = match remote.list() {
-            Ok(list) => match list.first() {
-                None => {
-                    error!("Remote repository seems empty for {}", p.id);
-                    return;
-                }
-                Some(head) => head.oid(),
+            Ok(list) => match find_remote_oid(list, reference.as_deref()) {
+                None => match &reference {
+                    Some(r) => {
+                        error!("Failed to find {} in remote repository for {}", r, recipe);
+                        continue;
+                    }
+                    None => {
+                        error!("Remote repository seems empty for {}", recipe);
+                        continue;
+                    }
+                },
+                Some(oid) => oid,
             },
             Err(_) => {
-                error!("Failed to get list from remote repository for {}", p.id);
-                return;
+                error!("Failed to get list from remote repository for {}", recipe);
+                continue;
             }
         };
 
-        if remote_oid.to_string() == package.hash { return; }
-
-        title!("⚙" ,"Upgrading {}", p.id);
-        install(Some(p.clone().id), false);
-    });
+        if remote_oid.to_string() == package.hash {
+            continue;
+        }
 
-    match db.unlock() {
-        Ok(_) => {}
-        Err(_) => {
-            warning!("Database already unlocked");
+        title!("⚙", "Upgrading {}", recipe);
+        let split: Vec<String> = packages.iter().map(|(id, _)| id.clone()).collect();
+        let name = match &package.repository {
+            Some(repository) => format!("{}/{}", repository, recipe),
+            None => recipe,
+        };
+        match &package.pinned {
+            Some(pinned) => install(vec![format!("{}@{}", name, pinned)], false, false, split)?,
+            None => install(vec![name], false, false, split)?,
         }
-    };
+    }
+
+    Ok(())
 }
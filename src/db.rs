@@ -66,7 +66,7 @@ impl Db {
                     } else {
                         if unsafe { kill(pid.parse().unwrap(), 0) } == 0 {
                             return Err(Error::new(
-                                ErrorKind::Other,
+                                ErrorKind::WouldBlock,
                                 format!("Another process is locking the db (pid = {})", pid),
                             ));
                         } else {
@@ -87,6 +87,11 @@ impl Db {
         Ok(())
     }
 
+    /// Whether this process holds the lock
+    pub fn is_locked(&self) -> bool {
+        self.lock
+    }
+
     pub fn unlock(&mut self) -> Result<(), Error> {
         if self.lock {
             std::fs::remove_file(self.base.join("lock"))?;
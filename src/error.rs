@@ -0,0 +1,91 @@
+use std::fmt::{Display, Formatter};
+
+/// Errors returned by lulu commands
+///
+/// Each kind of error is mapped to its own exit code so that scripts can tell them apart.
+#[derive(Debug)]
+pub enum LuluError {
+    /// Anything which doesn't fit in another category
+    Other(String),
+    /// /etc/lulu.conf is missing or invalid
+    Config(String),
+    /// The database can't be opened, read or written
+    Db(String),
+    /// Another process is holding the database lock
+    DbLocked(String),
+    /// The package is not known by lulu
+    PackageNotFound(String),
+    /// A LULU.toml file is missing or invalid
+    Manifest(String),
+    /// A git operation failed
+    Git(String),
+    /// An apt operation failed
+    Apt(String),
+    /// A phase of the build failed
+    Build(String),
+    /// Lulu can't get the rights it needs
+    Permission(String),
+    /// The signature of the source is missing or bad
+    Verification(String),
+}
+
+impl LuluError {
+    /// Exit code of the process when the command fails with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LuluError::Other(_) => 1,
+            LuluError::Config(_) => 3,
+            LuluError::Db(_) => 4,
+            LuluError::DbLocked(_) => 5,
+            LuluError::PackageNotFound(_) => 6,
+            LuluError::Manifest(_) => 7,
+            LuluError::Git(_) => 8,
+            LuluError::Apt(_) => 9,
+            LuluError::Build(_) => 10,
+            LuluError::Permission(_) => 11,
+            LuluError::Verification(_) => 12,
+        }
+    }
+
+    /// Rebuild an error from the exit code and the message of a child process
+    pub fn from_exit_code(code: i32, message: String) -> LuluError {
+        let message = if message.is_empty() {
+            format!("Child process failed with status {}", code)
+        } else {
+            message
+        };
+        match code {
+            3 => LuluError::Config(message),
+            4 => LuluError::Db(message),
+            5 => LuluError::DbLocked(message),
+            6 => LuluError::PackageNotFound(message),
+            7 => LuluError::Manifest(message),
+            8 => LuluError::Git(message),
+            9 => LuluError::Apt(message),
+            10 => LuluError::Build(message),
+            11 => LuluError::Permission(message),
+            12 => LuluError::Verification(message),
+            _ => LuluError::Other(message),
+        }
+    }
+}
+
+impl Display for LuluError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuluError::Other(message)
+            | LuluError::Config(message)
+            | LuluError::Db(message)
+            | LuluError::DbLocked(message)
+            | LuluError::PackageNotFound(message)
+            | LuluError::Manifest(message)
+            | LuluError::Git(message)
+            | LuluError::Apt(message)
+            | LuluError::Build(message)
+            | LuluError::Permission(message)
+            | LuluError::Verification(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LuluError {}
@@ -1,15 +1,21 @@
 mod commands;
 mod db;
+mod error;
 mod model;
 mod package;
 mod utils;
 
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 use log::trace;
 use serde::Serialize;
-use yansi::Paint;
+use yansi::{Color, Paint};
 
-use crate::commands::{install, list, remove, setup, update, upgrade};
+use crate::commands::{
+    autoremove, build, cache, install, list, log, remove, repo_add, repo_index, repo_list,
+    repo_remove, setup, update, upgrade, BuildOptions,
+};
 
 /// Concept of package manager built on top of apt for handling git repositories
 #[derive(Parser)]
@@ -32,15 +38,68 @@ struct Cli {
 enum Commands {
     /// Install packages
     Install {
-        /// Package to install
+        /// Packages to install
         ///
-        /// Can be a package name if lulu is connected to a repository, a git repository with LULU.toml file or can be blank in which case it will fallback
+        /// Each one can be a package name if lulu is connected to a repository, a git repository with LULU.toml file, a directory with a LULU.toml file or can be blank in which case it will fallback
         /// to the current directory (if a valid LULU.toml file exists).
-        name: Option<String>,
+        ///
+        /// A branch, tag or commit of the source can be chosen with `name@ref` (or `@ref` for the current directory),
+        /// and the repository of the recipe with `repository/name`.
+        ///
+        /// Several packages are built together: build dependencies are installed once and built packages in a single transaction.
+        names: Vec<String>,
 
         /// Do not install built package
         #[arg(short, long)]
         no_install: bool,
+
+        /// Build in an isolated build root instead of installing build dependencies on the host
+        ///
+        /// Needs bubblewrap. Can be enabled for every build with `isolated = true` in the `[build]` section of /etc/lulu.conf.
+        #[arg(long)]
+        isolated: bool,
+
+        /// Packages of a split recipe to install (comma separated), only the main package by default
+        ///
+        /// Only usable when installing a single package.
+        #[arg(long, value_delimiter = ',')]
+        split: Vec<String>,
+    },
+    /// Build a package without installing it
+    ///
+    /// Nothing is recorded in the db and root is only needed if build dependencies are missing.
+    Build {
+        /// Package to build
+        ///
+        /// Can be a package name if lulu is connected to a repository, a git repository with LULU.toml file, a directory with a LULU.toml file or can be blank in which case it will fallback
+        /// to the current directory (if a valid LULU.toml file exists).
+        ///
+        /// A branch, tag or commit of the source can be chosen with `name@ref` (or `@ref` for the current directory).
+        name: Option<String>,
+
+        /// Directory where built packages are written
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+
+        /// Build in an isolated build root
+        #[arg(long)]
+        isolated: bool,
+
+        /// Do not run the check script
+        #[arg(long)]
+        skip_check: bool,
+
+        /// Do not run the prepare script
+        #[arg(long)]
+        no_prepare: bool,
+
+        /// Keep the source directory of the previous build and resume it after its last completed phase
+        #[arg(long)]
+        keep_srcdir: bool,
+
+        /// Run the build from this phase, keeping the source directory of the previous build, which must exist
+        #[arg(long, value_parser = ["prepare", "build", "check", "package"])]
+        from_phase: Option<String>,
     },
     /// Setup lulu db
     ///
@@ -63,12 +122,76 @@ enum Commands {
         #[arg(short, long)]
         purge: bool,
     },
+    /// Remove build dependencies left by interrupted or failed builds
+    Autoremove {},
     /// List packages
     List {
         /// List installed packages
         #[arg(short, long)]
         installed: bool,
     },
+    /// Show the size of the source cache
+    ///
+    /// Sources are mirrored in /var/cache/lulu (or ~/.cache/lulu when not run as root) so that builds only fetch new commits.
+    Cache {
+        /// Remove every mirror of the cache
+        #[arg(short, long)]
+        clean: bool,
+    },
+    /// Show logs of the builds of a package
+    ///
+    /// Output of each phase of a build is kept in /var/log/lulu/<package>/<timestamp> (or ~/.local/state/lulu/log when not run as root).
+    Log {
+        /// Package whose builds to show
+        name: String,
+
+        /// Build to show (its timestamp), the latest one by default
+        #[arg(short, long)]
+        build: Option<String>,
+
+        /// Only show the log of this phase (prepare, build, check or package)
+        #[arg(short, long)]
+        phase: Option<String>,
+
+        /// List builds instead of showing one
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Manage recipe repositories
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum RepoCommands {
+    /// Add a repository to /etc/lulu.conf
+    Add {
+        /// Unique name of the repository, used in `repository/name`
+        name: String,
+
+        /// Git url of the repository, or a local directory read in place
+        source: String,
+
+        /// Branch to follow, the default branch of the remote by default
+        #[arg(short, long)]
+        branch: Option<String>,
+    },
+    /// Remove a repository from /etc/lulu.conf and forget its recipes
+    Remove {
+        /// Name of the repository
+        name: String,
+    },
+    /// List repositories of /etc/lulu.conf
+    List {},
+    /// Write lulu-index.json into a recipe repository
+    ///
+    /// The index lists the recipes of the repository so that `lulu update` doesn't have to read every LULU.toml.
+    Index {
+        /// Root of the recipe repository, the current directory by default
+        path: Option<PathBuf>,
+    },
 }
 
 fn main() {
@@ -84,28 +207,78 @@ fn main() {
         Paint::disable()
     }
 
-    match &cli.command {
-        Some(Commands::Install { name, no_install }) => {
-            trace!("{:?}", name);
-            install(name.to_owned(), no_install.to_owned());
-        }
-        Some(Commands::Setup { .. }) => {
-            setup();
-        }
-        Some(Commands::Update { no_check }) => {
-            update(no_check.to_owned());
+    let result = match &cli.command {
+        Some(Commands::Install {
+            names,
+            no_install,
+            isolated,
+            split,
+        }) => {
+            trace!("{:?}", names);
+            install(
+                names.to_owned(),
+                no_install.to_owned(),
+                isolated.to_owned(),
+                split.to_owned(),
+            )
         }
+        Some(Commands::Build {
+            name,
+            output,
+            isolated,
+            skip_check,
+            no_prepare,
+            keep_srcdir,
+            from_phase,
+        }) => build(
+            name.to_owned(),
+            output.to_owned(),
+            isolated.to_owned(),
+            BuildOptions {
+                skip_check: skip_check.to_owned(),
+                no_prepare: no_prepare.to_owned(),
+                keep_srcdir: keep_srcdir.to_owned() || from_phase.is_some(),
+                from_phase: from_phase.to_owned(),
+            },
+        ),
+        Some(Commands::Setup { .. }) => setup(),
+        Some(Commands::Update { no_check }) => update(no_check.to_owned()),
         Some(Commands::Upgrade { .. }) => upgrade(),
-        Some(Commands::Remove { name, purge }) => {
-            remove(name.to_owned(), purge.to_owned());
-        }
-        Some(Commands::List { installed }) => {
-            list(installed.to_owned());
-        }
+        Some(Commands::Remove { name, purge }) => remove(name.to_owned(), purge.to_owned()),
+        Some(Commands::List { installed }) => list(installed.to_owned()),
+        Some(Commands::Autoremove { .. }) => autoremove(),
+        Some(Commands::Cache { clean }) => cache(clean.to_owned()),
+        Some(Commands::Log {
+            name,
+            build,
+            phase,
+            list,
+        }) => log(
+            name.to_owned(),
+            build.to_owned(),
+            phase.to_owned(),
+            list.to_owned(),
+        ),
+        Some(Commands::Repo { command }) => match command {
+            RepoCommands::Add {
+                name,
+                source,
+                branch,
+            } => repo_add(name.to_owned(), source.to_owned(), branch.to_owned()),
+            RepoCommands::Remove { name } => repo_remove(name.to_owned()),
+            RepoCommands::List { .. } => repo_list(),
+            RepoCommands::Index { path } => repo_index(path.to_owned()),
+        },
+        // Repositories which failed to update don't prevent upgrading from the others
         None => {
-            update(true);
-            upgrade();
+            let updated = update(true);
+            upgrade().and(updated)
         }
+    };
+
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
     }
 }
 
@@ -1,21 +1,113 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub ignore: Vec<String>,
+    #[serde(default)]
     pub repositories: Vec<Repository>,
+    #[serde(default)]
+    pub build: BuildConfig,
+    #[serde(default)]
+    pub verify: VerifyConfig,
+}
+
+#[derive(Deserialize, Default)]
+pub struct BuildConfig {
+    /// Build packages in an isolated build root instead of installing build dependencies on the host
+    #[serde(default)]
+    pub isolated: bool,
+    /// Variables exported to the scripts of every recipe, recipes can override them
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct VerifyConfig {
+    /// Keyring holding trusted keys, /etc/lulu/trustedkeys.gpg by default
+    pub keyring: Option<String>,
+    /// Refuse to build sources which are not signed by a trusted key
+    #[serde(default)]
+    pub required: bool,
 }
 
 #[derive(Deserialize)]
 pub struct Repository {
     pub name: String,
     pub source: String,
+    /// Branch to follow, the default branch of the remote if none
+    #[serde(default, alias = "ref")]
+    pub branch: Option<String>,
+    /// When several repositories have a package, the one with the highest priority is used
+    #[serde(default)]
+    pub priority: i32,
+    /// Globs of the recipe directories to register, all of them if empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of the recipe directories to ignore, they win over `include`
+    #[serde(default)]
+    pub exclude: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl Repository {
+    /// Directory of the repository when its source is a local directory (`/path` or `file:///path`)
+    /// read in place without git, none for git urls
+    pub fn local_path(&self) -> Option<PathBuf> {
+        match self.source.strip_prefix("file://") {
+            Some(path) => Some(PathBuf::from(path)),
+            None if self.source.starts_with('/') => Some(PathBuf::from(&self.source)),
+            None => None,
+        }
+    }
+
+    /// Directory where the recipes of the repository are read
+    pub fn root(&self) -> PathBuf {
+        self.local_path()
+            .unwrap_or_else(|| Path::new("/var/lib/lulu/repositories").join(&self.name))
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
 pub struct DbPackage {
+    /// Repository of the recipe used by default
     pub repository: String,
     pub path: String,
+    /// Description of the recipe used by default, from the index of the repository
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Recipes of every repository having the package, the one used by default first
+    #[serde(default)]
+    pub recipes: Vec<Recipe>,
+}
+
+impl DbPackage {
+    /// Recipe of a repository, the one used by default if none
+    pub fn recipe(&self, repository: Option<&str>) -> Option<Recipe> {
+        match repository {
+            None => Some(Recipe {
+                repository: self.repository.clone(),
+                path: self.path.clone(),
+                description: self.description.clone(),
+            }),
+            Some(repository) if repository == self.repository => self.recipe(None),
+            Some(repository) => self
+                .recipes
+                .iter()
+                .find(|recipe| recipe.repository == repository)
+                .cloned(),
+        }
+    }
+}
+
+/// Where the recipe of a package is in a repository
+#[derive(Deserialize, Serialize, PartialEq, Clone)]
+pub struct Recipe {
+    pub repository: String,
+    pub path: String,
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -23,4 +115,25 @@ pub struct Installed {
     pub version: String,
     pub hash: String,
     pub source: String,
+    /// Revision asked by the user when installing (`lulu install name@ref`)
+    #[serde(default)]
+    pub pinned: Option<String>,
+    /// Recipe the package was built from when it's a split package
+    #[serde(default)]
+    pub recipe: Option<String>,
+    /// Repository the recipe came from, none for git urls and local recipes
+    #[serde(default)]
+    pub repository: Option<String>,
+}
+
+/// An apt package installed by lulu to build another package
+#[derive(Deserialize, Serialize)]
+pub struct BuildDependency {
+    /// Package which was being built
+    pub package: String,
+    /// Process which installed it, leftovers of running builds aren't removed
+    pub pid: i32,
+    /// Boot and start time of the process, so that a reused pid isn't taken for the build
+    #[serde(default)]
+    pub started: Option<String>,
 }
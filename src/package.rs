@@ -7,6 +7,57 @@ pub struct Lulu {
     pub package: Package,
     pub dependencies: Dependencies,
     pub script: Script,
+    /// Additional packages built from the same recipe
+    #[serde(default)]
+    pub split: BTreeMap<String, Split>,
+    /// Variables exported to the scripts
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Lulu {
+    /// Packages produced by the recipe, the main package first
+    pub fn outputs(&self) -> Vec<Output> {
+        let mut outputs = vec![Output {
+            name: self.package.name.clone(),
+            description: self.package.description.clone(),
+            provides: self.package.provides.clone(),
+            pre: self.dependencies.pre.clone(),
+            runtime: self.dependencies.runtime.clone(),
+            optional: self.dependencies.optional.clone(),
+            conflicts: self.package.conflicts.clone(),
+            replaces: self.package.replaces.clone(),
+            breaks: self.package.breaks.clone(),
+            suggests: self.package.suggests.clone(),
+            conffiles: self.package.conffiles.clone(),
+            noconffiles: self.package.noconffiles.clone(),
+            preinst: self.package.preinst.clone(),
+            postinst: self.package.postinst.clone(),
+            prerm: self.package.prerm.clone(),
+            postrm: self.package.postrm.clone(),
+            package: self.script.package.clone(),
+        }];
+        outputs.extend(self.split.iter().map(|(name, split)| Output {
+            name: name.clone(),
+            description: split.description.clone(),
+            provides: split.provides.clone(),
+            pre: split.dependencies.pre.clone(),
+            runtime: split.dependencies.runtime.clone(),
+            optional: split.dependencies.optional.clone(),
+            conflicts: split.conflicts.clone(),
+            replaces: split.replaces.clone(),
+            breaks: split.breaks.clone(),
+            suggests: split.suggests.clone(),
+            conffiles: split.conffiles.clone(),
+            noconffiles: split.noconffiles.clone(),
+            preinst: split.preinst.clone(),
+            postinst: split.postinst.clone(),
+            prerm: split.prerm.clone(),
+            postrm: split.postrm.clone(),
+            package: split.package.clone(),
+        }));
+        outputs
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,17 +67,135 @@ pub struct Package {
     pub description: String,
     pub url: Option<String>,
     pub source: String,
+    /// Branch, tag or any revision of `source` to build
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+    /// Tag of `source` to build
+    pub tag: Option<String>,
+    /// Commit of `source` to build
+    pub commit: Option<String>,
+    /// Epoch prefixed to the version, to be bumped when the versioning scheme changes
+    pub epoch: Option<u32>,
+    /// Fingerprints of the keys allowed to sign the source
+    #[serde(default)]
+    pub validpgpkeys: Vec<String>,
+    /// Additional files placed in the source directory before `prepare`
+    #[serde(default)]
+    pub sources: Vec<SourceFile>,
     pub arch: Vec<String>,
     pub license: Vec<String>,
     pub provides: Vec<String>,
+    /// Relations like `foo` or `foo (<< 2.0)`
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    #[serde(default)]
+    pub breaks: Vec<String>,
+    #[serde(default)]
+    pub suggests: Vec<String>,
+    pub section: Option<String>,
+    /// One of required, important, standard, optional or extra
+    pub priority: Option<String>,
+    /// Files kept by dpkg when they were modified, in addition to the ones under /etc
+    #[serde(default)]
+    pub conffiles: Vec<String>,
+    /// Files under /etc which are not conffiles
+    #[serde(default)]
+    pub noconffiles: Vec<String>,
     pub preinst: Option<String>,
     pub postinst: Option<String>,
     pub prerm: Option<String>,
     pub postrm: Option<String>,
 }
 
+impl Package {
+    /// Revision of the source pinned by the package, if any
+    ///
+    /// `commit` takes precedence over `tag` which takes precedence over `ref`.
+    pub fn pinned_ref(&self) -> Option<String> {
+        self.commit
+            .clone()
+            .or_else(|| self.tag.clone())
+            .or_else(|| self.reference.clone())
+    }
+}
+
+/// A package split from the main one, like `foo-dev` or `foo-doc`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Split {
+    pub description: String,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub dependencies: SplitDependencies,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    #[serde(default)]
+    pub breaks: Vec<String>,
+    #[serde(default)]
+    pub suggests: Vec<String>,
+    #[serde(default)]
+    pub conffiles: Vec<String>,
+    #[serde(default)]
+    pub noconffiles: Vec<String>,
+    pub preinst: Option<String>,
+    pub postinst: Option<String>,
+    pub prerm: Option<String>,
+    pub postrm: Option<String>,
+    /// Script installing the files of this package in $pkgdir
+    pub package: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SplitDependencies {
+    #[serde(default)]
+    pub pre: BTreeMap<String, Dependency>,
+    #[serde(default)]
+    pub runtime: BTreeMap<String, Dependency>,
+    #[serde(default)]
+    pub optional: BTreeMap<String, Dependency>,
+}
+
+/// A package produced by a recipe, either the main package or a split one
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub name: String,
+    pub description: String,
+    pub provides: Vec<String>,
+    pub pre: BTreeMap<String, Dependency>,
+    pub runtime: BTreeMap<String, Dependency>,
+    pub optional: BTreeMap<String, Dependency>,
+    pub conflicts: Vec<String>,
+    pub replaces: Vec<String>,
+    pub breaks: Vec<String>,
+    pub suggests: Vec<String>,
+    pub conffiles: Vec<String>,
+    pub noconffiles: Vec<String>,
+    pub preinst: Option<String>,
+    pub postinst: Option<String>,
+    pub prerm: Option<String>,
+    pub postrm: Option<String>,
+    /// Script installing the files of this package in $pkgdir
+    pub package: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SourceFile {
+    /// Http(s) url, `file://` url or path relative to the recipe directory
+    pub url: String,
+    pub sha256: String,
+    /// Path relative to the source directory, the file name of `url` by default
+    pub dest: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Dependencies {
+    /// Dependencies needed before the package is unpacked (Pre-Depends)
+    #[serde(default)]
+    pub pre: BTreeMap<String, Dependency>,
     pub runtime: BTreeMap<String, Dependency>,
     pub build: BTreeMap<String, Dependency>,
     pub optional: BTreeMap<String, Dependency>,
@@ -45,6 +214,11 @@ pub struct Script {
 pub struct Dependency {
     pub is: DependencyType,
     pub git: Option<String>,
+    /// Version constraint like `>= 1.2`
+    pub version: Option<String>,
+    /// Packages which can be used instead, like `libfoo-git` or `libbar (>= 2.0)`
+    #[serde(default)]
+    pub alternatives: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -0,0 +1,171 @@
+//! Logs of builds
+//!
+//! Output of each phase of a build is written both to the terminal and to
+//! `<log dir>/<package>/<timestamp>/<phase>.log`, and the exit status and duration of each phase
+//! are recorded in `build.json` next to them.
+
+use std::env;
+use std::fs::{DirBuilder, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// Where build logs are stored when lulu is run as root
+pub const LOG_DIR: &str = "/var/log/lulu";
+
+/// Where build logs are stored
+///
+/// It's /var/log/lulu for root and ~/.local/state/lulu/log (or $XDG_STATE_HOME/lulu/log) for
+/// other users.
+pub fn log_dir() -> PathBuf {
+    if sudo::check() == sudo::RunningAs::Root {
+        return Path::new(LOG_DIR).to_path_buf();
+    }
+    match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).join("lulu").join("log"),
+        _ => Path::new(&env::var("HOME").unwrap_or_default())
+            .join(".local")
+            .join("state")
+            .join("lulu")
+            .join("log"),
+    }
+}
+
+/// Result of a phase of a build
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhaseRecord {
+    pub phase: String,
+    /// Exit code of the phase, none if it was killed by a signal
+    pub status: Option<i32>,
+    pub success: bool,
+    /// Duration in seconds
+    pub duration: f64,
+}
+
+/// Logs of one build of a package
+pub struct BuildLog {
+    pub path: PathBuf,
+}
+
+impl BuildLog {
+    /// Create the log directory of a new build of `package`
+    ///
+    /// The directory is given to the user who ran sudo since builds are run as this user.
+    pub fn new(package: &str) -> Result<BuildLog, Error> {
+        let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let path = log_dir().join(package).join(timestamp);
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        builder.create(&path)?;
+
+        if let (Ok(uid), Ok(gid)) = (env::var("SUDO_UID"), env::var("SUDO_GID")) {
+            if let (Ok(uid), Ok(gid)) = (uid.parse::<u32>(), gid.parse::<u32>()) {
+                let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(BuildLog { path })
+    }
+
+    /// Open an existing build log directory
+    pub fn open(path: PathBuf) -> BuildLog {
+        BuildLog { path }
+    }
+
+    /// Path of the log of a phase
+    pub fn phase_path(&self, phase: &str) -> PathBuf {
+        self.path.join(format!("{}.log", phase))
+    }
+
+    /// Phases recorded so far
+    pub fn records(&self) -> Vec<PhaseRecord> {
+        match std::fs::read_to_string(self.path.join("build.json")) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn record(&self, record: PhaseRecord) -> Result<(), Error> {
+        let mut records = self.records();
+        records.push(record);
+        let serialized = serde_json::to_string_pretty(&records)?;
+        File::create(self.path.join("build.json"))?.write_all(serialized.as_bytes())
+    }
+
+    /// Run a phase, writing its output both to the terminal and to its log
+    pub fn run(&self, command: &mut Command, phase: &str) -> Result<ExitStatus, Error> {
+        let log = Arc::new(Mutex::new(File::create(self.phase_path(phase))?));
+        let start = Instant::now();
+
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().map(|out| {
+            let log = log.clone();
+            thread::spawn(move || tee(out, std::io::stdout(), log))
+        });
+        let stderr = child.stderr.take().map(|err| {
+            let log = log.clone();
+            thread::spawn(move || tee(err, std::io::stderr(), log))
+        });
+
+        let status = child.wait()?;
+        for handle in [stdout, stderr].into_iter().flatten() {
+            let _ = handle.join();
+        }
+
+        self.record(PhaseRecord {
+            phase: phase.to_string(),
+            status: status.code(),
+            success: status.success(),
+            duration: start.elapsed().as_secs_f64(),
+        })?;
+
+        Ok(status)
+    }
+}
+
+fn tee<R: Read, W: Write>(mut input: R, mut output: W, log: Arc<Mutex<File>>) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let _ = output.write_all(&buf[..n]);
+        let _ = output.flush();
+        if let Ok(mut log) = log.lock() {
+            let _ = log.write_all(&buf[..n]);
+        }
+    }
+}
+
+/// Builds of a package which have logs, oldest first
+pub fn builds(package: &str) -> Vec<BuildLog> {
+    let mut dirs = vec![log_dir()];
+    if !dirs.contains(&Path::new(LOG_DIR).to_path_buf()) {
+        dirs.push(Path::new(LOG_DIR).to_path_buf());
+    }
+
+    let mut builds: Vec<PathBuf> = dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir.join(package)).ok())
+        .flat_map(|rd| rd.flatten().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    builds.sort_by_key(|path| path.file_name().map(|name| name.to_os_string()));
+
+    builds.into_iter().map(BuildLog::open).collect()
+}
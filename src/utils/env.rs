@@ -0,0 +1,144 @@
+//! Environment of the scripts of a recipe
+//!
+//! Scripts get, from the lowest to the highest precedence:
+//! - defaults (`MAKEFLAGS` unless already set, `PREFIX`)
+//! - variables of `[build.env]` in the configuration
+//! - variables of `[env]` in the recipe
+//!
+//! Variables describing the build (`pkgname`, `srcdir`...) are set by lulu and can't be overridden.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Prefix where packages install their files
+pub const PREFIX: &str = "/usr";
+
+/// Variables set by lulu
+pub const RESERVED: [&str; 9] = [
+    "basedir",
+    "srcdir",
+    "pkgdir",
+    "pkgname",
+    "pkgver",
+    "CARCH",
+    "DEB_HOST_ARCH",
+    "SOURCE_COMMIT",
+    "SOURCE_DATE_EPOCH",
+];
+
+/// Variables exported to scripts
+pub struct ScriptEnv {
+    /// Variables set on the command, defaults first
+    vars: Vec<(String, String)>,
+    /// Variables exported at the start of scripts, so that bash expands them
+    exports: Vec<(String, String)>,
+}
+
+impl ScriptEnv {
+    /// Environment with the defaults and `tables` exported in order
+    pub fn new(tables: &[&BTreeMap<String, String>]) -> ScriptEnv {
+        let mut vars = vec![];
+        if std::env::var_os("MAKEFLAGS").is_none() {
+            let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+            vars.push(("MAKEFLAGS".to_string(), format!("-j{}", jobs)));
+        }
+        vars.push(("PREFIX".to_string(), PREFIX.to_string()));
+
+        let exports = tables
+            .iter()
+            .flat_map(|table| table.iter())
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        ScriptEnv { vars, exports }
+    }
+
+    /// Set a variable of lulu, it wins over the exported ones
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.push((name.to_string(), value.to_string()));
+    }
+
+    /// Set the variables on a command
+    pub fn apply(&self, command: &mut Command) {
+        command.envs(self.vars.iter().map(|(name, value)| (name, value)));
+    }
+
+    /// Prefix a script with the exports
+    ///
+    /// Values are expanded like in a double quoted string, so `$PATH:$HOME/.cargo/bin` works.
+    pub fn script(&self, script: &str) -> String {
+        let mut prefixed = String::new();
+        for (name, value) in &self.exports {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            prefixed.push_str(&format!("export {}=\"{}\"\n", name, value));
+        }
+        prefixed.push_str(script);
+        prefixed
+    }
+}
+
+/// Check that variables of a table can be exported
+pub fn validate(table: &BTreeMap<String, String>) -> Result<(), String> {
+    for name in table.keys() {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("Invalid variable name {:?}", name));
+        }
+        if RESERVED.contains(&name.as_str()) {
+            return Err(format!("{} is set by lulu and can't be overridden", name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_names() {
+        assert!(validate(&table(&[("CFLAGS", "-O2"), ("_my_var2", "")])).is_ok());
+        assert!(validate(&table(&[("2FAST", "")])).is_err());
+        assert!(validate(&table(&[("MY-VAR", "")])).is_err());
+        assert!(validate(&table(&[("", "")])).is_err());
+        assert!(validate(&table(&[("pkgdir", "/tmp")])).is_err());
+        assert!(validate(&table(&[("SOURCE_DATE_EPOCH", "0")])).is_err());
+    }
+
+    #[test]
+    fn script_exports_tables_in_order() {
+        let config = table(&[("CFLAGS", "-O2"), ("PATH", "$PATH:$HOME/.cargo/bin")]);
+        let recipe = table(&[("CFLAGS", "-O3 \"quoted\" \\n")]);
+        let env = ScriptEnv::new(&[&config, &recipe]);
+        assert_eq!(
+            env.script("make\n"),
+            "export CFLAGS=\"-O2\"\n\
+             export PATH=\"$PATH:$HOME/.cargo/bin\"\n\
+             export CFLAGS=\"-O3 \\\"quoted\\\" \\\\n\"\n\
+             make\n"
+        );
+    }
+
+    #[test]
+    fn variables_of_lulu_win() {
+        let mut env = ScriptEnv::new(&[]);
+        env.set("PREFIX", "/opt");
+        env.set("pkgname", "foo");
+        let mut command = Command::new("true");
+        env.apply(&mut command);
+        let vars: BTreeMap<_, _> = command
+            .get_envs()
+            .map(|(name, value)| (name.to_owned(), value.map(|v| v.to_owned())))
+            .collect();
+        assert_eq!(vars[std::ffi::OsStr::new("PREFIX")], Some("/opt".into()));
+        assert_eq!(vars[std::ffi::OsStr::new("pkgname")], Some("foo".into()));
+        assert_eq!(env.script("true"), "true");
+    }
+}
@@ -0,0 +1,74 @@
+//! Glob patterns matching paths of recipes in a repository
+//!
+//! Patterns are matched against `/` separated paths relative to the root of the repository:
+//! - `*` matches any characters of a path component
+//! - `?` matches one character of a path component
+//! - `**` as a whole component matches any number of components
+//!
+//! A pattern matching a directory also matches everything below it, so `games` matches
+//! `games/foo`.
+
+/// Whether `pattern` matches `path` or one of its parent directories
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+    let path: Vec<&str> = path.trim_matches('/').split('/').collect();
+    (1..=path.len()).any(|len| match_components(&pattern, &path[..len]))
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            None => false,
+            Some((component, path)) => {
+                let first: Vec<char> = first.chars().collect();
+                let component: Vec<char> = component.chars().collect();
+                match_component(&first, &component) && match_components(rest, path)
+            }
+        },
+    }
+}
+
+fn match_component(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_component(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn star_stays_in_a_component() {
+        assert!(matches("games/*", "games/foo"));
+        assert!(matches("*-git", "foo-git"));
+        assert!(!matches("*-git", "games/foo-git"));
+        assert!(!matches("games/*-git", "games/foo"));
+        assert!(matches("games/fo?", "games/foo"));
+        assert!(!matches("games/fo?", "games/fooo"));
+    }
+
+    #[test]
+    fn double_star_spans_components() {
+        assert!(matches("**/*-git", "foo-git"));
+        assert!(matches("**/*-git", "games/foo-git"));
+        assert!(matches("**/*-git", "games/arcade/foo-git"));
+        assert!(!matches("**/*-git", "games/foo"));
+        assert!(matches("games/**/foo", "games/foo"));
+        assert!(matches("games/**/foo", "games/arcade/foo"));
+        assert!(!matches("games/**/foo", "libs/foo"));
+    }
+
+    #[test]
+    fn directories_match_what_is_below() {
+        assert!(matches("games", "games/foo"));
+        assert!(matches("games/", "games/arcade/foo"));
+        assert!(!matches("games", "gamesfoo"));
+        assert!(!matches("games/foo", "games"));
+    }
+}
@@ -0,0 +1,264 @@
+//! Index of a recipe repository
+//!
+//! `lulu repo index` writes a `lulu-index.json` file at the root of a recipe repository, listing
+//! its recipes so that `lulu update` doesn't have to parse every LULU.toml.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::package::{Dependency, Lulu};
+use crate::utils::lulu::lulu_file;
+
+/// Name of the index file, at the root of the repository
+pub const INDEX_FILE: &str = "lulu-index.json";
+
+/// Version of the format of the index
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    pub version: u32,
+    pub packages: Vec<IndexEntry>,
+}
+
+/// A recipe of the repository
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexEntry {
+    pub name: String,
+    /// Revision of the source pinned by the recipe, `null` when it builds the latest commit
+    pub version: Option<String>,
+    pub description: String,
+    pub provides: Vec<String>,
+    pub dependencies: IndexDependencies,
+    /// Directory of the recipe, relative to the root of the repository
+    pub path: String,
+}
+
+/// Names of the dependencies of a recipe
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexDependencies {
+    pub pre: Vec<String>,
+    pub runtime: Vec<String>,
+    pub build: Vec<String>,
+    pub optional: Vec<String>,
+}
+
+impl IndexEntry {
+    pub fn new(lulu: &Lulu, path: &Path) -> IndexEntry {
+        let names = |dependencies: &BTreeMap<String, Dependency>| {
+            dependencies.keys().cloned().collect::<Vec<String>>()
+        };
+        IndexEntry {
+            name: lulu.package.name.clone(),
+            version: lulu.package.pinned_ref(),
+            description: lulu.package.description.clone(),
+            provides: lulu.package.provides.clone(),
+            dependencies: IndexDependencies {
+                pre: names(&lulu.dependencies.pre),
+                runtime: names(&lulu.dependencies.runtime),
+                build: names(&lulu.dependencies.build),
+                optional: names(&lulu.dependencies.optional),
+            },
+            path: path.display().to_string(),
+        }
+    }
+}
+
+/// Find the recipes of a repository: directories with a LULU.toml file, at any depth
+///
+/// Hidden directories and directories of recipes are not searched. Recipes which can't be read
+/// are returned as errors so that the caller can report them.
+pub fn scan(root: &Path) -> Result<Vec<Result<IndexEntry, String>>, std::io::Error> {
+    let mut entries = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for child in std::fs::read_dir(&dir)? {
+            let child = child?;
+            let path = child.path();
+            if child.file_name().to_string_lossy().starts_with('.') || !path.is_dir() {
+                continue;
+            }
+            if path.join("LULU.toml").is_file() {
+                entries.push(read_entry(root, &path));
+            } else if !child.file_type()?.is_symlink() {
+                // Symbolic links are not followed to avoid loops
+                dirs.push(path);
+            }
+        }
+    }
+    entries.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => a.path.cmp(&b.path),
+        (a, b) => a.is_err().cmp(&b.is_err()),
+    });
+    Ok(entries)
+}
+
+fn read_entry(root: &Path, path: &Path) -> Result<IndexEntry, String> {
+    let relative = path.strip_prefix(root).unwrap_or(path).to_path_buf();
+    match lulu_file(path.join("LULU.toml")) {
+        Ok(Ok(lulu)) => Ok(IndexEntry::new(&lulu, &relative)),
+        Ok(Err(e)) => Err(format!(
+            "{}/LULU.toml is not deserializable: {}",
+            relative.display(),
+            e
+        )),
+        Err(e) => Err(format!(
+            "{}/LULU.toml is not readable: {}",
+            relative.display(),
+            e
+        )),
+    }
+}
+
+/// Names having several recipes, with their paths
+pub fn duplicates(entries: &[IndexEntry]) -> Vec<(String, Vec<String>)> {
+    let mut paths = BTreeMap::<String, Vec<String>>::new();
+    for entry in entries {
+        paths
+            .entry(entry.name.clone())
+            .or_default()
+            .push(entry.path.clone());
+    }
+    paths
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect()
+}
+
+/// Check that the path of an entry stays inside the repository: relative, without `..`
+pub fn check_path(path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+    let inside = path.components().next().is_some()
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside {
+        return Err(format!(
+            "{} is not a directory of the repository",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Read the index of a repository, none if it has no index
+pub fn read(root: &Path) -> Result<Option<Index>, String> {
+    let path = root.join(INDEX_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let file = File::open(&path).map_err(|e| format!("Failed to open {}: {}", INDEX_FILE, e))?;
+    let index: Index = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("{} is not deserializable: {}", INDEX_FILE, e))?;
+    if index.version != INDEX_VERSION {
+        return Err(format!(
+            "{} has version {}, only version {} is supported",
+            INDEX_FILE, index.version, INDEX_VERSION
+        ));
+    }
+    Ok(Some(index))
+}
+
+/// Write the index of a repository and return its path
+pub fn write(root: &Path, packages: Vec<IndexEntry>) -> Result<PathBuf, String> {
+    let path = root.join(INDEX_FILE);
+    let index = Index {
+        version: INDEX_VERSION,
+        packages,
+    };
+    let serialized = serde_json::to_string_pretty(&index)
+        .map_err(|e| format!("Failed to serialize index: {}", e))?;
+    File::create(&path)
+        .and_then(|mut file| file.write_all(format!("{}\n", serialized).as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::write_recipe;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn scan_finds_recipes_at_any_depth() {
+        let repo = TempDir::new().unwrap();
+        write_recipe(&repo.path().join("foo"), "foo");
+        write_recipe(&repo.path().join("games/arcade/bar"), "bar");
+        // Recipes below a recipe or in hidden directories are not searched
+        write_recipe(&repo.path().join("foo/nested"), "nested");
+        write_recipe(&repo.path().join(".hidden/baz"), "baz");
+        fs::create_dir_all(repo.path().join("broken")).unwrap();
+        fs::write(repo.path().join("broken/LULU.toml"), "[package]").unwrap();
+
+        let entries = scan(repo.path()).unwrap();
+        let (found, errors): (Vec<_>, Vec<_>) = entries.into_iter().partition(Result::is_ok);
+        let found: Vec<IndexEntry> = found.into_iter().filter_map(Result::ok).collect();
+        let errors: Vec<String> = errors.into_iter().filter_map(Result::err).collect();
+        let paths: Vec<(&str, &str)> = found
+            .iter()
+            .map(|e| (e.name.as_str(), e.path.as_str()))
+            .collect();
+        assert_eq!(paths, [("foo", "foo"), ("bar", "games/arcade/bar")]);
+        assert_eq!(found[0].description, "Recipe of foo");
+        assert_eq!(found[0].dependencies.build, ["make"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("broken/LULU.toml"));
+    }
+
+    #[test]
+    fn duplicated_names() {
+        let repo = TempDir::new().unwrap();
+        write_recipe(&repo.path().join("foo"), "foo");
+        write_recipe(&repo.path().join("legacy/foo"), "foo");
+        write_recipe(&repo.path().join("bar"), "bar");
+
+        let entries: Vec<IndexEntry> = scan(repo.path())
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            duplicates(&entries),
+            [(
+                "foo".to_string(),
+                vec!["foo".to_string(), "legacy/foo".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let repo = TempDir::new().unwrap();
+        assert!(read(repo.path()).unwrap().is_none());
+        write_recipe(&repo.path().join("foo"), "foo");
+
+        let entries = scan(repo.path()).unwrap().into_iter().map(Result::unwrap);
+        write(repo.path(), entries.collect()).unwrap();
+        let index = read(repo.path()).unwrap().unwrap();
+        assert_eq!(index.packages.len(), 1);
+        assert_eq!(index.packages[0].path, "foo");
+
+        fs::write(
+            repo.path().join(INDEX_FILE),
+            r#"{"version": 2, "packages": []}"#,
+        )
+        .unwrap();
+        assert!(read(repo.path()).is_err());
+    }
+
+    #[test]
+    fn paths_stay_in_the_repository() {
+        assert!(check_path("foo").is_ok());
+        assert!(check_path("games/foo").is_ok());
+        assert!(check_path("").is_err());
+        assert!(check_path("/etc").is_err());
+        assert!(check_path("../foo").is_err());
+        assert!(check_path("games/../../foo").is_err());
+    }
+}
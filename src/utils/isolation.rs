@@ -0,0 +1,179 @@
+//! Isolated build environment
+//!
+//! Instead of being installed on the host, build dependencies are downloaded in the apt cache and
+//! extracted in a build root. Build scripts are then run with bubblewrap, the build root being
+//! mounted over the host filesystem with overlays, so the host dpkg database is never touched.
+//!
+//! Overlays need bubblewrap 0.10 or later.
+
+use std::env;
+use std::fs::DirBuilder;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Oldest version of bubblewrap supporting overlays
+pub const BWRAP_MIN_VERSION: (u32, u32) = (0, 10);
+
+/// Check that bubblewrap is installed and recent enough to run isolated builds
+pub fn check_bwrap() -> Result<(), String> {
+    let output = Command::new("bwrap")
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("Failed to execute bwrap: {}", e))?;
+    // Like `bubblewrap 0.10.0`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.trim().trim_start_matches("bubblewrap").trim();
+    match parse_version(version) {
+        Some(found) if found >= BWRAP_MIN_VERSION => Ok(()),
+        _ => Err(format!(
+            "Isolated builds need bubblewrap {}.{} or later, found {:?}",
+            BWRAP_MIN_VERSION.0, BWRAP_MIN_VERSION.1, version
+        )),
+    }
+}
+
+/// Major and minor numbers of a version like `0.10.0`
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut numbers = version.split('.').map(|n| n.parse::<u32>());
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+/// Root directory in which build dependencies are extracted
+pub struct BuildRoot {
+    pub path: PathBuf,
+    binds: Vec<PathBuf>,
+}
+
+impl BuildRoot {
+    /// Create an empty build root, `binds` are directories which will be writable by build scripts
+    pub fn new(path: PathBuf, binds: Vec<PathBuf>) -> Result<BuildRoot, Error> {
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        builder.create(&path)?;
+
+        Ok(BuildRoot { path, binds })
+    }
+
+    /// Extract a .deb archive in the build root without running any maintainer script
+    pub fn extract(&self, archive: &Path) -> Result<(), Error> {
+        let status = Command::new("dpkg-deb")
+            .arg("-x")
+            .arg(archive)
+            .arg(&self.path)
+            .status()?;
+        if !status.success() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Failed to extract {}", archive.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Move top level directories which are symlinks on the host (like `/lib` on merged /usr
+    /// systems) to their target, so they are not hidden by the overlays
+    pub fn merge_usr(&self) -> Result<(), Error> {
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let host = Path::new("/").join(entry.file_name());
+            if !host.is_symlink() || !entry.path().is_dir() {
+                continue;
+            }
+            let target = self.path.join(std::fs::read_link(&host)?);
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            builder.create(&target)?;
+            let status = Command::new("cp")
+                .arg("-a")
+                .arg(entry.path().join("."))
+                .arg(&target)
+                .status()?;
+            if !status.success() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Failed to merge {}", entry.path().display()),
+                ));
+            }
+            std::fs::remove_dir_all(entry.path())?;
+        }
+        Ok(())
+    }
+
+    /// Build a command running `program` inside the build root
+    pub fn command(&self, program: &str) -> Command {
+        let mut command = Command::new("bwrap");
+        command
+            .arg("--die-with-parent")
+            .args(["--ro-bind", "/", "/"])
+            .args(["--dev", "/dev"])
+            .args(["--proc", "/proc"])
+            .args(["--tmpfs", "/tmp"]);
+
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                if !entry.path().is_dir() {
+                    continue;
+                }
+                let host = Path::new("/").join(entry.file_name());
+                if host.is_dir() {
+                    command
+                        .arg("--overlay-src")
+                        .arg(&host)
+                        .arg("--overlay-src")
+                        .arg(entry.path())
+                        .arg("--tmp-overlay")
+                        .arg(&host);
+                } else {
+                    command.arg("--ro-bind").arg(entry.path()).arg(&host);
+                }
+            }
+        }
+
+        let mut binds = self.binds.clone();
+        if let Ok(home) = env::var("HOME") {
+            binds.push(PathBuf::from(home));
+        }
+        for bind in binds {
+            command.arg("--bind").arg(&bind).arg(&bind);
+        }
+
+        command.arg("--").arg(program);
+        command
+    }
+
+    /// Remove the build root
+    pub fn remove(self) -> Result<(), Error> {
+        std::fs::remove_dir_all(self.path)
+    }
+}
+
+/// Path of a package archive downloaded by apt in its cache
+pub fn archive_path(name: &str, version: &str, arch: &str) -> PathBuf {
+    Path::new("/var/cache/apt/archives").join(format!(
+        "{}_{}_{}.deb",
+        name,
+        version.replace(':', "%3a"),
+        arch
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_version;
+
+    #[test]
+    fn bwrap_versions() {
+        assert_eq!(parse_version("0.10.0"), Some((0, 10)));
+        assert_eq!(parse_version("0.6.1"), Some((0, 6)));
+        assert!(parse_version("0.6.1") < Some((0, 10)));
+        assert_eq!(parse_version("0"), None);
+        assert_eq!(parse_version(""), None);
+    }
+}
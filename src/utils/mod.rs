@@ -1,13 +1,21 @@
+pub mod build_log;
 pub mod display;
+pub mod env;
+pub mod glob;
+pub mod index;
+pub mod isolation;
+pub mod relation;
+pub mod signature;
+pub mod source_cache;
+pub mod sources;
+#[cfg(test)]
+pub mod testing;
+pub mod version;
 
 pub mod git {
     use std::io::Write;
 
-    fn do_fetch<'a>(
-        repo: &'a git2::Repository,
-        refs: &[&str],
-        remote: &'a mut git2::Remote,
-    ) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+    fn fetch(remote: &mut git2::Remote, refs: &[&str], prune: bool) -> Result<(), git2::Error> {
         let mut cb = git2::RemoteCallbacks::new();
 
         // Print out our transfer progress.
@@ -36,6 +44,9 @@ pub mod git {
         // Always fetch all tags.
         // Perform a download and also update tips
         fo.download_tags(git2::AutotagOption::All);
+        if prune {
+            fo.prune(git2::FetchPrune::On);
+        }
         println!("Fetching {} for repo", remote.name().unwrap());
         remote.fetch(refs, Some(&mut fo), None)?;
 
@@ -60,136 +71,131 @@ pub mod git {
             );
         }
 
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        Ok(repo.reference_to_annotated_commit(&fetch_head)?)
-    }
-
-    fn fast_forward(
-        repo: &git2::Repository,
-        lb: &mut git2::Reference,
-        rc: &git2::AnnotatedCommit,
-    ) -> Result<(), git2::Error> {
-        let name = match lb.name() {
-            Some(s) => s.to_string(),
-            None => String::from_utf8_lossy(lb.name_bytes()).to_string(),
-        };
-        let msg = format!("Fast-Forward: Setting {} to id: {}", name, rc.id());
-        println!("{}", msg);
-        lb.set_target(rc.id(), &msg)?;
-        repo.set_head(&name)?;
-        repo.checkout_head(Some(
-            git2::build::CheckoutBuilder::default()
-                // For some reason the force is required to make the working directory actually get updated
-                // I suspect we should be adding some logic to handle dirty working directory states
-                // but this is just an example so maybe not.
-                .force(),
-        ))?;
         Ok(())
     }
 
-    fn normal_merge(
-        repo: &git2::Repository,
-        local: &git2::AnnotatedCommit,
-        remote: &git2::AnnotatedCommit,
-    ) -> Result<(), git2::Error> {
-        let local_tree = repo.find_commit(local.id())?.tree()?;
-        let remote_tree = repo.find_commit(remote.id())?.tree()?;
-        let ancestor = repo
-            .find_commit(repo.merge_base(local.id(), remote.id())?)?
-            .tree()?;
-        let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
-
-        if idx.has_conflicts() {
-            println!("Merge conflicts detected...");
-            repo.checkout_index(Some(&mut idx), None)?;
-            return Ok(());
+    /// References mirrored from a source, other ones like pull requests are not needed to build
+    pub const MIRROR_REFSPECS: [&str; 2] =
+        ["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+
+    /// Fetch the branches and tags of a mirror from its `origin` remote and point its HEAD to the
+    /// default branch of the remote
+    pub fn fetch_mirror(repo: &git2::Repository) -> Result<(), git2::Error> {
+        let mut remote = repo.find_remote("origin")?;
+        remote.connect(git2::Direction::Fetch)?;
+        let head = remote.default_branch()?;
+        remote.disconnect()?;
+
+        fetch(&mut remote, &MIRROR_REFSPECS, true)?;
+
+        // Mirrors created by earlier versions of lulu have every reference of the source
+        let mut stale = vec![];
+        for reference in repo.references()? {
+            if let Some(name) = reference?.name() {
+                if !name.starts_with("refs/heads/") && !name.starts_with("refs/tags/") {
+                    stale.push(name.to_string());
+                }
+            }
+        }
+        for name in stale {
+            repo.find_reference(&name)?.delete()?;
+        }
+
+        if let Some(head) = head.as_str() {
+            repo.set_head(head)?;
         }
-        let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
-        // now create the merge commit
-        let msg = format!("Merge: {} into {}", remote.id(), local.id());
-        let sig = repo.signature()?;
-        let local_commit = repo.find_commit(local.id())?;
-        let remote_commit = repo.find_commit(remote.id())?;
-        // Do our merge commit and set current branch head to that commit.
-        let _merge_commit = repo.commit(
-            Some("HEAD"),
-            &sig,
-            &sig,
-            &msg,
-            &result_tree,
-            &[&local_commit, &remote_commit],
-        )?;
-        // Set working tree to match head.
-        repo.checkout_head(None)?;
         Ok(())
     }
 
-    fn do_merge<'a>(
-        repo: &'a git2::Repository,
-        remote_branch: &str,
-        fetch_commit: git2::AnnotatedCommit<'a>,
-    ) -> Result<(), git2::Error> {
-        // 1. do a merge analysis
-        let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-        // 2. Do the appropriate merge
-        if analysis.0.is_fast_forward() {
-            println!("Doing a fast forward");
-            // do a fast forward
-            let refname = format!("refs/heads/{}", remote_branch);
-            match repo.find_reference(&refname) {
-                Ok(mut r) => {
-                    fast_forward(repo, &mut r, &fetch_commit)?;
-                }
-                Err(_) => {
-                    // The branch doesn't exist so just set the reference to the
-                    // commit directly. Usually this is because you are pulling
-                    // into an empty repository.
-                    repo.reference(
-                        &refname,
-                        fetch_commit.id(),
-                        true,
-                        &format!("Setting {} to {}", remote_branch, fetch_commit.id()),
-                    )?;
-                    repo.set_head(&refname)?;
-                    repo.checkout_head(Some(
-                        git2::build::CheckoutBuilder::default()
-                            .allow_conflicts(true)
-                            .conflict_style_merge(true)
-                            .force(),
-                    ))?;
-                }
-            };
-        } else if analysis.0.is_normal() {
-            // do a normal merge
-            let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-            normal_merge(&repo, &head_commit, &fetch_commit)?;
-        } else {
-            println!("Nothing to do...");
+    /// Checkout a branch, tag or commit in detached HEAD mode
+    ///
+    /// Branches which only exist on `origin` are also looked up.
+    pub fn checkout(repo: &git2::Repository, reference: &str) -> Result<git2::Oid, git2::Error> {
+        let object = match repo.revparse_single(reference) {
+            Ok(o) => o,
+            Err(e) => match repo.revparse_single(&format!("origin/{}", reference)) {
+                Ok(o) => o,
+                Err(_) => return Err(e),
+            },
+        };
+        let commit = object.peel_to_commit()?;
+        repo.checkout_tree(commit.as_object(), None)?;
+        repo.set_head_detached(commit.id())?;
+        Ok(commit.id())
+    }
+
+    /// Find the commit pointed by a reference among the heads advertised by a remote
+    ///
+    /// Without reference the remote HEAD is used. Tags are peeled to their commit.
+    pub fn find_remote_oid(
+        heads: &[git2::RemoteHead],
+        reference: Option<&str>,
+    ) -> Option<git2::Oid> {
+        match reference {
+            None => heads.first().map(|head| head.oid()),
+            Some(reference) => [
+                format!("refs/tags/{}^{{}}", reference),
+                format!("refs/tags/{}", reference),
+                format!("refs/heads/{}", reference),
+                reference.to_string(),
+            ]
+            .iter()
+            .find_map(|name| {
+                heads
+                    .iter()
+                    .find(|head| head.name() == name)
+                    .map(|head| head.oid())
+            }),
         }
-        Ok(())
     }
 
-    pub fn pull(
-        repo: git2::Repository,
+    /// Tell if a reference looks like a (possibly abbreviated) commit hash
+    pub fn is_commit_hash(reference: &str) -> bool {
+        reference.len() >= 4
+            && reference.len() <= 40
+            && reference.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    /// Fetch a branch of a remote and hard reset the repository onto it
+    ///
+    /// The local branch of the same name is created or moved and checked out, so that the
+    /// repository follows the branch even when it changed. Local changes are discarded.
+    pub fn reset_to_branch(
+        repo: &git2::Repository,
         remote_name: &str,
-        remote_branch: &str,
-    ) -> Result<(), git2::Error> {
+        branch: &str,
+    ) -> Result<git2::Oid, git2::Error> {
         let mut remote = repo.find_remote(remote_name)?;
-        let fetch_commit = do_fetch(&repo, &[remote_branch], &mut remote)?;
-        do_merge(&repo, &remote_branch, fetch_commit)
+        let refspec = format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, remote_name);
+        fetch(&mut remote, &[&refspec], false)?;
+        let commit = repo
+            .find_reference(&format!("refs/remotes/{}/{}", remote_name, branch))?
+            .peel_to_commit()?;
+
+        // The checked out branch can't be moved, resetting moves it
+        let head = format!("refs/heads/{}", branch);
+        let on_branch = repo
+            .head()
+            .map_or(false, |h| h.name() == Some(head.as_str()));
+        if !on_branch {
+            repo.branch(branch, &commit, true)?;
+            repo.set_head(&head)?;
+        }
+        repo.reset(commit.as_object(), git2::ResetType::Hard, None)?;
+        Ok(commit.id())
     }
 }
 
 pub mod lulu {
-    use crate::error;
+    use crate::error::LuluError;
     use crate::package::Lulu;
     use fork::{fork, Fork};
     use log::trace;
     use std::env;
-    use std::io::{Error, Read};
+    use std::fs::File;
+    use std::io::{Error, Read, Write};
+    use std::os::unix::io::FromRawFd;
     use std::path::Path;
-    use yansi::{Color, Paint};
 
     pub fn lulu_file<P: AsRef<Path>>(path: P) -> Result<Result<Lulu, toml::de::Error>, Error> {
         let file = std::fs::File::open(path)?;
@@ -199,21 +205,34 @@ pub mod lulu {
         Ok(toml::from_str(&contents))
     }
 
-    pub fn fork_wait<F>(child: F) -> i32
+    /// Run `child` in a forked process (as the user who ran sudo if any) and wait for it
+    ///
+    /// An error returned by the child is sent back to the parent through a pipe.
+    pub fn fork_wait<F>(child: F) -> Result<(), LuluError>
     where
-        F: Fn(),
+        F: Fn() -> Result<(), LuluError>,
     {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(LuluError::Other("Failed to create pipe".to_string()));
+        }
+
         let mut status: i32 = 0;
+        let mut message = String::new();
         match fork() {
             Ok(Fork::Parent(child)) => {
                 trace!(
                     "Continuing execution in parent process, new child has pid: {}",
                     child
                 );
+                unsafe { libc::close(fds[1]) };
+                let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+                let _ = reader.read_to_string(&mut message);
                 unsafe { libc::waitpid(child, &mut status, 0) };
                 trace!("Status is {}", status);
             }
             Ok(Fork::Child) => {
+                unsafe { libc::close(fds[0]) };
                 let sudo = env::var("SUDO_USER");
                 if sudo.is_ok() && sudo.unwrap() != "" {
                     let uid: u32 = env::var("SUDO_UID").unwrap().parse().unwrap();
@@ -222,46 +241,217 @@ pub mod lulu {
                     unsafe { libc::setgid(gid) };
                 }
 
-                child();
+                if let Err(e) = child() {
+                    let mut writer = unsafe { File::from_raw_fd(fds[1]) };
+                    let _ = writer.write_all(e.to_string().as_bytes());
+                    std::process::exit(e.exit_code());
+                }
 
                 std::process::exit(0);
             }
-            Err(_) => error!("Fork failed"),
+            Err(_) => {
+                unsafe {
+                    libc::close(fds[0]);
+                    libc::close(fds[1]);
+                };
+                return Err(LuluError::Other("Fork failed".to_string()));
+            }
+        }
+
+        let code = if libc::WIFEXITED(status) {
+            libc::WEXITSTATUS(status)
+        } else {
+            1
+        };
+        if code == 0 {
+            Ok(())
+        } else {
+            Err(LuluError::from_exit_code(code, message))
         }
-        status
     }
 }
 
-pub mod db {
-    use crate::db::Db;
-    use crate::error;
-    use std::io::Error;
+pub mod arch {
+    use deb_rust::DebArchitecture;
+    use std::process::Command;
+
+    /// Native architecture of the host as reported by dpkg
+    pub fn host_architecture() -> Option<String> {
+        let output = Command::new("dpkg")
+            .arg("--print-architecture")
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Map a Debian architecture name to its `DebArchitecture`
+    pub fn deb_architecture(name: &str) -> Option<DebArchitecture> {
+        match name {
+            "all" => Some(DebArchitecture::All),
+            "amd64" => Some(DebArchitecture::Amd64),
+            "arm64" => Some(DebArchitecture::Arm64),
+            "armel" => Some(DebArchitecture::Armel),
+            "armhf" => Some(DebArchitecture::Armhf),
+            "i386" => Some(DebArchitecture::I386),
+            "mipsel" => Some(DebArchitecture::Mipsel),
+            "mips64el" => Some(DebArchitecture::Mips64el),
+            "riscv64" => Some(DebArchitecture::Riscv64),
+            "s390x" => Some(DebArchitecture::S390x),
+            _ => None,
+        }
+    }
+
+    /// Choose the architecture of the package built on `host` from the `arch` field of a package
+    ///
+    /// `all` gives an architecture independent package, `any` or the host architecture give a
+    /// package for the host. Otherwise the host is not supported by the package.
+    pub fn package_architecture(arch: &[String], host: &str) -> Result<String, String> {
+        if arch.iter().any(|a| a == "all") {
+            return Ok("all".to_string());
+        }
+        if !arch.iter().any(|a| a == "any" || a == host) {
+            return Err(format!(
+                "Package only supports {} but host is {}",
+                arch.join(", "),
+                host
+            ));
+        }
+        match deb_architecture(host) {
+            Some(_) => Ok(host.to_string()),
+            None => Err(format!("Architecture {} is not supported by lulu", host)),
+        }
+    }
+}
+
+pub mod config {
+    use crate::error::LuluError;
+    use crate::model::Config;
     use std::path::Path;
+
+    pub const CONFIG_PATH: &str = "/etc/lulu.conf";
+
+    pub fn read_config() -> Result<Config, LuluError> {
+        let contents = std::fs::read_to_string(CONFIG_PATH)
+            .map_err(|e| LuluError::Config(format!("Failed to read {}: {}", CONFIG_PATH, e)))?;
+        toml::from_str(&contents)
+            .map_err(|e| LuluError::Config(format!("Invalid {}: {}", CONFIG_PATH, e)))
+    }
+
+    /// Read the config as a document which keeps its comments and formatting when edited
+    pub fn read_config_document() -> Result<toml_edit::Document, LuluError> {
+        let contents = std::fs::read_to_string(CONFIG_PATH)
+            .map_err(|e| LuluError::Config(format!("Failed to read {}: {}", CONFIG_PATH, e)))?;
+        contents
+            .parse::<toml_edit::Document>()
+            .map_err(|e| LuluError::Config(format!("Invalid {}: {}", CONFIG_PATH, e)))
+    }
+
+    /// Replace the config with an edited document
+    ///
+    /// The document is checked to be a valid config first, and the file is replaced at once so
+    /// that a concurrent read never sees it half written.
+    pub fn write_config_document(document: &toml_edit::Document) -> Result<(), LuluError> {
+        let contents = document.to_string();
+        toml::from_str::<Config>(&contents)
+            .map_err(|e| LuluError::Config(format!("Edited config is invalid: {}", e)))?;
+
+        let path = Path::new(CONFIG_PATH);
+        let temporary = path.with_extension("conf.new");
+        std::fs::write(&temporary, contents)
+            .and_then(|_| std::fs::metadata(path))
+            .and_then(|metadata| std::fs::set_permissions(&temporary, metadata.permissions()))
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&temporary);
+                LuluError::Config(format!("Failed to write {}: {}", CONFIG_PATH, e))
+            })
+    }
+}
+
+pub mod privileges {
+    use crate::error::LuluError;
+    use crate::{tip, warning};
     use yansi::{Color, Paint};
 
-    pub fn open_db() -> Result<Db, Error> {
-        let db = match Db::new(Path::new("/var/lib/lulu/db").to_path_buf()) {
-            Ok(db) => db,
-            Err(e) => {
-                error!("Failed to open database");
-                return Err(e);
+    /// Make sure lulu runs as root, running it again with sudo if needed
+    pub fn run_as_root(command: &str) -> Result<(), LuluError> {
+        if sudo::check() != sudo::RunningAs::Root {
+            warning!("Lulu must be run as root");
+            if let Err(e) = sudo::with_env(&["USER", "HOME"]) {
+                tip!("Run lulu as root with `sudo lulu {}`", command);
+                return Err(LuluError::Permission(format!(
+                    "Failed to run as root: {}",
+                    e
+                )));
             }
-        };
+        }
+        Ok(())
+    }
+}
 
-        Ok(db)
+pub mod process {
+    use std::fs;
+
+    /// Identity of a running process which survives pid reuse: the boot it runs in and its
+    /// start time, none if it isn't running
+    pub fn start_id(pid: i32) -> Option<String> {
+        let boot = fs::read_to_string("/proc/sys/kernel/random/boot_id").ok()?;
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // Fields are counted after the command name, which may contain spaces, starttime is the 22nd
+        let start = stat.rsplit_once(')')?.1.split_whitespace().nth(19)?;
+        Some(format!("{}:{}", boot.trim(), start))
     }
+}
 
-    pub fn open_and_lock_db() -> Result<Db, Error> {
-        let mut db = open_db()?;
+pub mod db {
+    use crate::db::Db;
+    use crate::error::LuluError;
+    use std::io::ErrorKind;
+    use std::path::Path;
 
-        match db.lock() {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Failed to lock database");
-                return Err(e);
-            }
-        };
+    pub const DB_PATH: &str = "/var/lib/lulu/db";
 
+    pub fn open_db() -> Result<Db, LuluError> {
+        Db::new(Path::new(DB_PATH).to_path_buf())
+            .map_err(|e| LuluError::Db(format!("Failed to open database: {}", e)))
+    }
+
+    pub fn lock_db(db: &mut Db) -> Result<(), LuluError> {
+        db.lock().map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock => LuluError::DbLocked(e.to_string()),
+            _ => LuluError::Db(format!("Failed to lock database: {}", e)),
+        })
+    }
+
+    pub fn unlock_db(db: &mut Db) -> Result<(), LuluError> {
+        db.unlock()
+            .map_err(|e| LuluError::Db(format!("Failed to unlock database: {}", e)))
+    }
+
+    pub fn open_and_lock_db() -> Result<Db, LuluError> {
+        let mut db = open_db()?;
+        lock_db(&mut db)?;
         Ok(db)
     }
+
+    /// Run `f` with the db locked
+    ///
+    /// When this process already holds the lock, like `lulu upgrade` installing packages, the db
+    /// is left locked for it.
+    pub fn with_lock<T>(
+        db: &Db,
+        f: impl FnOnce(&Db) -> Result<T, LuluError>,
+    ) -> Result<T, LuluError> {
+        let mut db = db.clone();
+        if db.is_locked() {
+            return f(&db);
+        }
+        lock_db(&mut db)?;
+        let result = f(&db);
+        unlock_db(&mut db)?;
+        result
+    }
 }
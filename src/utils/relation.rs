@@ -0,0 +1,169 @@
+//! Relations between packages, as written in `Depends` or `Conflicts` fields of a control file
+
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+use rust_apt::util::cmp_versions;
+
+const OPERATORS: [&str; 5] = ["<<", "<=", "=", ">=", ">>"];
+
+/// A package name with an optional version constraint, like `libfoo (>= 1.2)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relation {
+    pub name: String,
+    /// Operator and version
+    pub constraint: Option<(String, String)>,
+}
+
+impl Relation {
+    /// Create a relation from a name and a constraint like `>= 1.2`
+    pub fn new(name: &str, constraint: Option<&str>) -> Result<Relation, String> {
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Invalid package name {:?}", name));
+        }
+        let constraint = match constraint {
+            Some(constraint) => Some(parse_constraint(constraint)?),
+            None => None,
+        };
+        Ok(Relation {
+            name: name.to_string(),
+            constraint,
+        })
+    }
+
+    /// Parse a relation like `libfoo` or `libfoo (>= 1.2)`
+    pub fn parse(relation: &str) -> Result<Relation, String> {
+        match relation.split_once('(') {
+            Some((name, constraint)) => match constraint.trim().strip_suffix(')') {
+                Some(constraint) => Relation::new(name, Some(constraint)),
+                None => Err(format!("Invalid relation {:?}", relation)),
+            },
+            None => Relation::new(relation, None),
+        }
+    }
+
+    /// Whether `version` satisfies the constraint
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        let (operator, required) = match &self.constraint {
+            Some(constraint) => constraint,
+            None => return true,
+        };
+        let ordering = cmp_versions(version, required);
+        match operator.as_str() {
+            "<<" => ordering == Ordering::Less,
+            "<=" => ordering != Ordering::Greater,
+            "=" => ordering == Ordering::Equal,
+            ">=" => ordering != Ordering::Less,
+            ">>" => ordering == Ordering::Greater,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.constraint {
+            Some((operator, version)) => write!(f, "{} ({} {})", self.name, operator, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Parse a constraint like `>= 1.2` into its operator and version
+fn parse_constraint(constraint: &str) -> Result<(String, String), String> {
+    let constraint = constraint.trim();
+    let split = constraint
+        .find(|c: char| !matches!(c, '<' | '>' | '='))
+        .unwrap_or(constraint.len());
+    let (operator, version) = constraint.split_at(split);
+    let version = version.trim();
+
+    if !OPERATORS.contains(&operator) {
+        return Err(format!(
+            "Invalid operator {:?} in {:?}, expected one of {}",
+            operator,
+            constraint,
+            OPERATORS.join(", ")
+        ));
+    }
+    if version.is_empty() || version.contains(char::is_whitespace) {
+        return Err(format!("Invalid version in {:?}", constraint));
+    }
+    Ok((operator.to_string(), version.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Relation;
+
+    fn constraint(operator: &str, version: &str) -> Option<(String, String)> {
+        Some((operator.to_string(), version.to_string()))
+    }
+
+    #[test]
+    fn parse_relations() {
+        let relation = Relation::parse("libfoo").unwrap();
+        assert_eq!(relation.name, "libfoo");
+        assert_eq!(relation.constraint, None);
+
+        let relation = Relation::parse("libfoo (>= 1.2)").unwrap();
+        assert_eq!(relation.name, "libfoo");
+        assert_eq!(relation.constraint, constraint(">=", "1.2"));
+        assert_eq!(relation.to_string(), "libfoo (>= 1.2)");
+
+        assert_eq!(
+            Relation::parse("libfoo (>>1.2)").unwrap().constraint,
+            constraint(">>", "1.2")
+        );
+        assert_eq!(
+            Relation::new("libfoo", Some("<< 2:1.0-1"))
+                .unwrap()
+                .constraint,
+            constraint("<<", "2:1.0-1")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_relations() {
+        assert!(Relation::parse("").is_err());
+        assert!(Relation::parse("lib foo").is_err());
+        assert!(Relation::parse("libfoo (>= 1.2").is_err());
+        assert!(Relation::parse("libfoo (> 1.2)").is_err());
+        assert!(Relation::parse("libfoo (< 1.2)").is_err());
+        assert!(Relation::parse("libfoo (== 1.2)").is_err());
+        assert!(Relation::parse("libfoo (>=)").is_err());
+        assert!(Relation::parse("libfoo (>= 1 2)").is_err());
+    }
+
+    #[test]
+    fn strict_operators() {
+        let later = Relation::parse("libfoo (>> 1.2)").unwrap();
+        assert!(later.satisfied_by("1.3"));
+        assert!(!later.satisfied_by("1.2"));
+        assert!(!later.satisfied_by("1.2~rc1"));
+
+        let earlier = Relation::parse("libfoo (<< 1.2)").unwrap();
+        assert!(earlier.satisfied_by("1.2~rc1"));
+        assert!(!earlier.satisfied_by("1.2"));
+        assert!(!earlier.satisfied_by("1:1.0"));
+    }
+
+    #[test]
+    fn inclusive_operators() {
+        let relation = Relation::parse("libfoo (>= 1.2)").unwrap();
+        assert!(relation.satisfied_by("1.2"));
+        assert!(relation.satisfied_by("1.10"));
+        assert!(!relation.satisfied_by("1.1"));
+
+        let relation = Relation::parse("libfoo (<= 1.2)").unwrap();
+        assert!(relation.satisfied_by("1.2"));
+        assert!(!relation.satisfied_by("1.2-1"));
+
+        let relation = Relation::parse("libfoo (= 1.2-1)").unwrap();
+        assert!(relation.satisfied_by("1.2-1"));
+        assert!(!relation.satisfied_by("1.2-2"));
+
+        assert!(Relation::parse("libfoo").unwrap().satisfied_by("0"));
+    }
+}
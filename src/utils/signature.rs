@@ -0,0 +1,180 @@
+//! Verification of signed sources
+//!
+//! Signatures of tags and commits are extracted with git2 then checked with `gpgv` against a trusted
+//! keyring.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use git2::{Oid, Repository};
+use yansi::{Color, Paint};
+
+use crate::warning;
+
+/// Default keyring holding the keys trusted by lulu
+pub const KEYRING_PATH: &str = "/etc/lulu/trustedkeys.gpg";
+
+const PGP_SIGNATURE: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+/// A detached signature and the data it signs
+pub struct Signature {
+    /// What is signed, like `tag v1.0` or `commit 1a2b3c4...`
+    pub object: String,
+    pub signature: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Find the signature of what is built
+///
+/// If `reference` is a signed annotated tag pointing to the commit checked out at HEAD its signature
+/// is used, otherwise the signature of the commit pointed by HEAD.
+pub fn find_signature(
+    repo: &Repository,
+    reference: Option<&str>,
+) -> Result<Option<Signature>, git2::Error> {
+    // A kept source directory may not be at the tag anymore
+    let head = repo.head()?.peel_to_commit()?.id();
+    if let Some(reference) = reference {
+        if let Some(signature) = tag_signature(repo, reference, head)? {
+            return Ok(Some(signature));
+        }
+    }
+
+    commit_signature(repo, head)
+}
+
+fn tag_signature(
+    repo: &Repository,
+    name: &str,
+    head: Oid,
+) -> Result<Option<Signature>, git2::Error> {
+    let (oid, commit) = match repo.find_reference(&format!("refs/tags/{}", name)) {
+        Ok(reference) => match reference.target() {
+            Some(oid) => (oid, reference.peel_to_commit()?.id()),
+            None => return Ok(None),
+        },
+        Err(_) => return Ok(None),
+    };
+    // Lightweight tags have no signature
+    if repo.find_tag(oid).is_err() {
+        return Ok(None);
+    }
+    if commit != head {
+        warning!(
+            "Tag {} doesn't point to the built commit {}, its signature is not used",
+            name,
+            head
+        );
+        return Ok(None);
+    }
+
+    let odb = repo.odb()?;
+    let object = odb.read(oid)?;
+    let data = object.data();
+    let start = match data
+        .windows(PGP_SIGNATURE.len())
+        .rposition(|window| window == PGP_SIGNATURE)
+    {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Signature {
+        object: format!("tag {}", name),
+        signature: data[start..].to_vec(),
+        payload: data[..start].to_vec(),
+    }))
+}
+
+fn commit_signature(repo: &Repository, oid: Oid) -> Result<Option<Signature>, git2::Error> {
+    match repo.extract_signature(&oid, None) {
+        Ok((signature, payload)) => Ok(Some(Signature {
+            object: format!("commit {}", oid),
+            signature: signature.to_vec(),
+            payload: payload.to_vec(),
+        })),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check a signature with gpgv and return the fingerprints of the signing key
+///
+/// The first fingerprint is the one of the (sub)key which made the signature, the second one the
+/// one of its primary key.
+pub fn verify(signature: &Signature, keyring: &Path) -> Result<Vec<String>, String> {
+    let id = uuid::Uuid::new_v4();
+    let signature_path = env::temp_dir().join(format!("lulu_{}.sig", id));
+    let payload_path = env::temp_dir().join(format!("lulu_{}.payload", id));
+
+    let result = write(&signature_path, &signature.signature)
+        .and_then(|_| write(&payload_path, &signature.payload))
+        .and_then(|_| {
+            Command::new("gpgv")
+                .arg("--status-fd")
+                .arg("1")
+                .arg("--keyring")
+                .arg(keyring)
+                .arg(&signature_path)
+                .arg(&payload_path)
+                .output()
+                .map_err(|e| format!("Failed to execute gpgv: {}", e))
+        });
+
+    let _ = std::fs::remove_file(&signature_path);
+    let _ = std::fs::remove_file(&payload_path);
+
+    let output = result?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let fingerprints: Vec<String> = status
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["[GNUPG:]", "VALIDSIG", fields @ ..] => Some(fields.to_vec()),
+                _ => None,
+            }
+        })
+        .flat_map(|fields| {
+            [fields.first(), fields.get(9)]
+                .into_iter()
+                .flatten()
+                .map(|fingerprint| fingerprint.to_string())
+                .collect::<Vec<String>>()
+        })
+        .collect();
+
+    if !output.status.success() || fingerprints.is_empty() {
+        let reason = status
+            .lines()
+            .find_map(|line| {
+                let line = line.strip_prefix("[GNUPG:] ")?;
+                ["BADSIG", "ERRSIG", "NO_PUBKEY", "EXPKEYSIG", "REVKEYSIG"]
+                    .iter()
+                    .find(|keyword| line.starts_with(*keyword))
+                    .map(|_| line.to_string())
+            })
+            .unwrap_or_else(|| String::from_utf8_lossy(&output.stderr).trim().to_string());
+        return Err(format!("Bad signature of {}: {}", signature.object, reason));
+    }
+
+    Ok(fingerprints)
+}
+
+/// Normalize a fingerprint so that it can be compared: upper case without spaces
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
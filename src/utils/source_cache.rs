@@ -0,0 +1,127 @@
+//! Persistent cache of git sources
+//!
+//! Each source url gets a bare mirror which is fetched incrementally, builds then get a fresh work
+//! tree cloned from this local mirror instead of cloning the whole repository over the network.
+
+use std::env;
+use std::fs::DirBuilder;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+
+use crate::utils::git::{fetch_mirror, MIRROR_REFSPECS};
+
+/// A mirror of a source repository
+pub struct Mirror {
+    pub url: String,
+    pub path: PathBuf,
+}
+
+/// Where mirrors are stored
+///
+/// It's /var/cache/lulu for root and ~/.cache/lulu (or $XDG_CACHE_HOME/lulu) for other users.
+pub fn cache_dir() -> PathBuf {
+    if sudo::check() == sudo::RunningAs::Root {
+        return Path::new("/var/cache/lulu").to_path_buf();
+    }
+    match env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).join("lulu"),
+        _ => Path::new(&env::var("HOME").unwrap_or_default())
+            .join(".cache")
+            .join("lulu"),
+    }
+}
+
+fn mirror_path(url: &str) -> PathBuf {
+    cache_dir()
+        .join("sources")
+        .join(format!("{}.git", url.replace([':', '/', '@'], "_")))
+}
+
+/// Create or update the mirror of a source repository
+pub fn mirror(url: &str) -> Result<Mirror, git2::Error> {
+    let path = mirror_path(url);
+    let repo = match Repository::open_bare(&path) {
+        Ok(repo) => repo,
+        Err(_) => {
+            let mut builder = DirBuilder::new();
+            builder.recursive(true);
+            builder
+                .create(&path)
+                .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+            let repo = Repository::init_bare(&path)?;
+            repo.remote_with_fetch("origin", url, MIRROR_REFSPECS[0])?;
+            repo.remote_add_fetch("origin", MIRROR_REFSPECS[1])?;
+            repo
+        }
+    };
+
+    fetch_mirror(&repo)?;
+
+    Ok(Mirror {
+        url: url.to_string(),
+        path,
+    })
+}
+
+/// Clone a fresh work tree of a mirror into `dest`, removing what was there before
+pub fn checkout(mirror: &Mirror, dest: &Path) -> Result<Repository, git2::Error> {
+    if dest.exists() {
+        std::fs::remove_dir_all(dest).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    }
+
+    let repo = Repository::clone(&mirror.path.display().to_string(), dest)?;
+    // Point back to the real source so that the work tree looks like a regular clone
+    repo.remote_set_url("origin", &mirror.url)?;
+
+    Ok(repo)
+}
+
+/// List mirrors of the cache
+pub fn mirrors() -> Vec<Mirror> {
+    let rd = match std::fs::read_dir(cache_dir().join("sources")) {
+        Ok(rd) => rd,
+        Err(_) => return vec![],
+    };
+    rd.flatten()
+        .filter_map(|entry| {
+            let repo = Repository::open_bare(entry.path()).ok()?;
+            let url = repo.find_remote("origin").ok()?.url()?.to_string();
+            Some(Mirror {
+                url,
+                path: entry.path(),
+            })
+        })
+        .collect()
+}
+
+/// Size in bytes of a directory and its content
+pub fn size(path: &Path) -> u64 {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match std::fs::read_dir(path) {
+        Ok(rd) => rd.flatten().map(|entry| size(&entry.path())).sum(),
+        Err(_) => 0,
+    }
+}
+
+/// Format a size in bytes for humans
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
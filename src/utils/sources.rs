@@ -0,0 +1,246 @@
+//! Additional source files of a package
+//!
+//! Each file listed in `sources` is copied or downloaded into the source directory and its sha256
+//! checksum is verified.
+
+use std::fs::DirBuilder;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use crate::package::SourceFile;
+
+/// Where a source file is placed, relative to the source directory
+///
+/// It's `dest` if set, otherwise the file name of `url`. It can't leave the source directory.
+pub fn destination(source: &SourceFile) -> Result<PathBuf, String> {
+    let dest = match &source.dest {
+        Some(dest) => PathBuf::from(dest),
+        None => {
+            let name = source
+                .url
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .split(['?', '#'])
+                .next()
+                .unwrap_or_default();
+            PathBuf::from(name)
+        }
+    };
+
+    if dest.as_os_str().is_empty()
+        || dest
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "Invalid destination {} for {}",
+            dest.display(),
+            source.url
+        ));
+    }
+    Ok(dest)
+}
+
+/// Copy or download a source file to `dest`
+///
+/// Urls can be http(s) urls, `file://` urls or paths relative to the recipe directory, which they
+/// can't leave.
+pub fn fetch(url: &str, recipe_dir: &Path, dest: &Path) -> Result<(), String> {
+    if let Some(parent) = dest.parent() {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        builder
+            .create(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let status = Command::new("curl")
+            .arg("--fail")
+            .arg("--location")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--output")
+            .arg(dest)
+            .arg(url)
+            .status()
+            .map_err(|e| format!("Failed to execute curl: {}", e))?;
+        if !status.success() {
+            return Err(format!("Failed to download {}", url));
+        }
+        return Ok(());
+    }
+
+    let path = match url.strip_prefix("file://") {
+        Some(path) => PathBuf::from(path),
+        None if url.contains("://") => return Err(format!("Unsupported url {}", url)),
+        None => recipe_file(recipe_dir, url)?,
+    };
+    std::fs::copy(&path, dest)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to copy {}: {}", path.display(), e))
+}
+
+/// Resolve a path relative to the recipe directory, refusing paths which leave it
+fn recipe_file(recipe_dir: &Path, path: &str) -> Result<PathBuf, String> {
+    if Path::new(path).is_absolute() {
+        return Err(format!("{} is absolute, use a file:// url", path));
+    }
+    let canonicalize = |path: &Path| {
+        path.canonicalize()
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+    let root = canonicalize(recipe_dir)?;
+    let file = canonicalize(&recipe_dir.join(path))?;
+    if !file.starts_with(&root) {
+        return Err(format!("{} is outside of the recipe directory", path));
+    }
+    Ok(file)
+}
+
+/// Sha256 checksum of a file, in lower case hexadecimal
+pub fn sha256(path: &Path) -> Result<String, String> {
+    let output = Command::new("sha256sum")
+        .arg("--binary")
+        .arg(path)
+        .output()
+        .map_err(|e| format!("Failed to execute sha256sum: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to compute checksum of {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|sum| sum.to_lowercase())
+        .ok_or(format!("Failed to compute checksum of {}", path.display()))
+}
+
+/// Copy a directory and its content, except `.git`
+///
+/// Symbolic links are copied as links rather than followed.
+pub fn copy_dir(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    builder.create(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            // A previous copy may have left it
+            if target.symlink_metadata().is_ok() {
+                std::fs::remove_file(&target)?;
+            }
+            symlink(std::fs::read_link(entry.path())?, target)?;
+        } else if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn source(url: &str, dest: Option<&str>) -> SourceFile {
+        SourceFile {
+            url: url.to_string(),
+            sha256: String::new(),
+            dest: dest.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn destination_of_urls() {
+        let dest = |url, dest| destination(&source(url, dest));
+        assert_eq!(
+            dest("https://example.com/foo-1.0.tar.gz?raw=1", None),
+            Ok(PathBuf::from("foo-1.0.tar.gz"))
+        );
+        assert_eq!(
+            dest("patches/fix.patch", None),
+            Ok(PathBuf::from("fix.patch"))
+        );
+        assert_eq!(
+            dest("fix.patch", Some("debian/patches/fix.patch")),
+            Ok(PathBuf::from("debian/patches/fix.patch"))
+        );
+    }
+
+    #[test]
+    fn destination_stays_in_the_source_directory() {
+        let dest = |url, dest| destination(&source(url, dest));
+        assert!(dest("https://example.com/", None).is_ok());
+        assert!(dest("https://example.com/..", None).is_err());
+        assert!(dest("fix.patch", Some("../fix.patch")).is_err());
+        assert!(dest("fix.patch", Some("debian/../../fix.patch")).is_err());
+        assert!(dest("fix.patch", Some("/etc/fix.patch")).is_err());
+        assert!(dest("fix.patch", Some("")).is_err());
+    }
+
+    #[test]
+    fn recipe_files_stay_in_the_recipe_directory() {
+        let dir = TempDir::new().unwrap();
+        let recipe = dir.path().join("recipe");
+        fs::create_dir_all(recipe.join("patches")).unwrap();
+        fs::write(recipe.join("patches/fix.patch"), "").unwrap();
+        fs::write(dir.path().join("secret"), "").unwrap();
+        symlink(dir.path().join("secret"), recipe.join("link")).unwrap();
+
+        assert!(recipe_file(&recipe, "patches/fix.patch").is_ok());
+        assert!(recipe_file(&recipe, "patches/../patches/fix.patch").is_ok());
+        assert!(recipe_file(&recipe, "../secret").is_err());
+        assert!(recipe_file(&recipe, "link").is_err());
+        assert!(recipe_file(&recipe, &dir.path().join("secret").display().to_string()).is_err());
+        assert!(recipe_file(&recipe, "missing").is_err());
+    }
+
+    #[test]
+    fn copy_dir_skips_git_and_keeps_links() {
+        let dir = TempDir::new().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::create_dir_all(from.join(".git")).unwrap();
+        fs::create_dir_all(from.join("src")).unwrap();
+        fs::write(from.join("src/main.c"), "int main;").unwrap();
+        symlink("src/main.c", from.join("main.c")).unwrap();
+
+        copy_dir(&from, &to).unwrap();
+        // A second copy replaces the links
+        copy_dir(&from, &to).unwrap();
+        assert!(!to.join(".git").exists());
+        assert_eq!(
+            fs::read_to_string(to.join("src/main.c")).unwrap(),
+            "int main;"
+        );
+        assert_eq!(
+            fs::read_link(to.join("main.c")).unwrap(),
+            Path::new("src/main.c")
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("empty"), "").unwrap();
+        assert_eq!(
+            sha256(&dir.path().join("empty")),
+            Ok("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string())
+        );
+    }
+}
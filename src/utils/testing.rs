@@ -0,0 +1,30 @@
+//! Helpers shared by tests
+
+use std::fs;
+use std::path::Path;
+
+/// Write a minimal LULU.toml of the package `name` into `dir`, creating it
+pub fn write_recipe(dir: &Path, name: &str) {
+    fs::create_dir_all(dir).unwrap();
+    let recipe = format!(
+        r#"[package]
+name = "{0}"
+maintainers = []
+description = "Recipe of {0}"
+source = "https://example.com/{0}.git"
+arch = ["any"]
+license = []
+provides = []
+
+[dependencies]
+runtime = {{}}
+build = {{ make = {{ is = "APT" }} }}
+optional = {{}}
+
+[script]
+package = "true"
+"#,
+        name
+    );
+    fs::write(dir.join("LULU.toml"), recipe).unwrap();
+}
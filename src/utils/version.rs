@@ -0,0 +1,189 @@
+//! Debian versions of built packages
+//!
+//! Versions look like `[epoch:]upstream[-revision]`, see
+//! https://www.debian.org/doc/debian-policy/ch-controlfields.html#version
+
+use chrono::{TimeZone, Utc};
+
+/// Epoch of versions derived from git
+///
+/// Earlier releases of lulu recorded versions like `1.4.2.37.g1a2b3c4` or a commit hash, which sort
+/// above the current ones. The epoch makes the first rebuild an upgrade.
+pub const GIT_EPOCH: u32 = 1;
+
+/// Version of a commit `count` commits after `tag`, like `1.4.2` or `1.4.2+r37.g1a2b3c4`
+///
+/// None if no upstream version can be made from the tag.
+pub fn from_tag(tag: &str, count: usize, hash: &str) -> Option<String> {
+    let tag = tag.strip_prefix(['v', 'V']).unwrap_or(tag);
+    let upstream = tag.replace(['-', '_'], ".");
+    if !is_upstream(&upstream, false) {
+        return None;
+    }
+    if count == 0 {
+        Some(upstream)
+    } else {
+        Some(format!("{}+r{}.g{}", upstream, count, short(hash)))
+    }
+}
+
+/// Version of a commit when there is no tag, like `0~git20230115.1a2b3c4`
+///
+/// `0~` sorts it before any tagged version.
+pub fn from_commit(time: i64, hash: &str) -> String {
+    let date = Utc
+        .timestamp_opt(time, 0)
+        .single()
+        .map(|date| date.format("%Y%m%d").to_string())
+        .unwrap_or_default();
+    format!("0~git{}.{}", date, short(hash))
+}
+
+/// Prefix a version with an epoch
+pub fn with_epoch(version: &str, epoch: Option<u32>) -> Result<String, String> {
+    match epoch {
+        None => Ok(version.to_string()),
+        Some(_) if version.contains(':') => Err(format!(
+            "Version {} already has an epoch, epoch of the package can't be added",
+            version
+        )),
+        Some(epoch) => Ok(format!("{}:{}", epoch, version)),
+    }
+}
+
+/// Version without its epoch, as used in file names
+pub fn without_epoch(version: &str) -> &str {
+    match version.split_once(':') {
+        Some((_, version)) => version,
+        None => version,
+    }
+}
+
+/// Check that a version follows the Debian syntax
+pub fn validate(version: &str) -> Result<(), String> {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (Some(epoch), rest),
+        None => (None, version),
+    };
+    if let Some(epoch) = epoch {
+        if epoch.is_empty() || !epoch.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("Invalid epoch in version {:?}", version));
+        }
+    }
+
+    let (upstream, revision) = match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (upstream, Some(revision)),
+        None => (rest, None),
+    };
+    if let Some(revision) = revision {
+        if revision.is_empty()
+            || !revision
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+.~".contains(c))
+        {
+            return Err(format!("Invalid revision in version {:?}", version));
+        }
+    }
+    if !is_upstream(upstream, revision.is_some()) {
+        return Err(format!(
+            "Invalid version {:?}: it should start with a digit and only contain letters, digits and . + ~{}",
+            version,
+            if revision.is_some() { " -" } else { "" }
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `upstream` is a valid upstream version, hyphens are only allowed with a revision
+fn is_upstream(upstream: &str, hyphen: bool) -> bool {
+    upstream.starts_with(|c: char| c.is_ascii_digit())
+        && upstream
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".+~".contains(c) || (hyphen && c == '-'))
+}
+
+fn short(hash: &str) -> &str {
+    &hash[..hash.len().min(7)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_apt::util::cmp_versions;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn versions_of_tags() {
+        assert_eq!(
+            from_tag("v1.4.2", 0, "1a2b3c4d5e"),
+            Some("1.4.2".to_string())
+        );
+        assert_eq!(
+            from_tag("1.4.2", 37, "1a2b3c4d5e"),
+            Some("1.4.2+r37.g1a2b3c4".to_string())
+        );
+        assert_eq!(from_tag("release-1_2", 0, ""), None);
+        assert_eq!(from_tag("1_2-3", 0, ""), Some("1.2.3".to_string()));
+    }
+
+    #[test]
+    fn versions_of_commits() {
+        assert_eq!(
+            from_commit(1673740800, "1a2b3c4d5e"),
+            "0~git20230115.1a2b3c4"
+        );
+    }
+
+    #[test]
+    fn epochs() {
+        assert_eq!(with_epoch("1.0", None), Ok("1.0".to_string()));
+        assert_eq!(with_epoch("1.0", Some(2)), Ok("2:1.0".to_string()));
+        assert!(with_epoch("1:1.0", Some(2)).is_err());
+        assert_eq!(without_epoch("2:1.0-1"), "1.0-1");
+        assert_eq!(without_epoch("1.0-1"), "1.0-1");
+    }
+
+    #[test]
+    fn validation() {
+        assert!(validate("1.0").is_ok());
+        assert!(validate("1:1.0-2").is_ok());
+        assert!(validate("1.0-rc1-2").is_ok());
+        assert!(validate("0~git20230115.1a2b3c4").is_ok());
+        assert!(validate("a1.0").is_err());
+        assert!(validate(":1.0").is_err());
+        assert!(validate("x:1.0").is_err());
+        assert!(validate("1.0-").is_err());
+        assert!(validate("1.0_1").is_err());
+    }
+
+    #[test]
+    fn git_versions_sort_above_old_ones() {
+        let old = ["1.4.2.37.g1a2b3c4", "1a2b3c4d5e6f7a8b9c0d"];
+        let new = [
+            with_epoch(&from_commit(1673740800, "1a2b3c4"), Some(GIT_EPOCH)).unwrap(),
+            with_epoch("1.4.2", Some(GIT_EPOCH)).unwrap(),
+        ];
+        for old in old {
+            for new in &new {
+                assert_eq!(
+                    cmp_versions(new, old),
+                    Ordering::Greater,
+                    "{} > {}",
+                    new,
+                    old
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn untagged_commits_sort_before_tags() {
+        let untagged = from_commit(1673740800, "1a2b3c4");
+        assert_eq!(cmp_versions(&untagged, "0.1"), Ordering::Less);
+        assert_eq!(cmp_versions(&untagged, "0"), Ordering::Less);
+        assert_eq!(
+            cmp_versions("1.4.2", &from_tag("1.4.2", 1, "1a2b3c4").unwrap()),
+            Ordering::Less
+        );
+    }
+}